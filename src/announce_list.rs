@@ -0,0 +1,127 @@
+extern crate rand;
+
+use bencode::Bencode;
+use rand::seq::SliceRandom;
+
+// trackers grouped into tiers as described by BEP 12, every tracker in a tier is tried before moving on to the next tier
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnnounceList{
+    pub tiers: Vec<Vec<String>>
+}
+
+fn to_url(entry: &Bencode) -> Option<String>{
+    match entry{
+        Bencode::ByteString(e) => match String::from_utf8(e.clone()){
+            Ok(e) if !e.trim().is_empty() => Some(e.trim().to_string()),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+impl AnnounceList{
+
+    pub fn from_tiers(tiers: Vec<Vec<String>>) -> AnnounceList{
+        let mut ret = AnnounceList{
+            tiers: Vec::new()
+        };
+
+        for tier in tiers{
+            let mut new_tier: Vec<String> = Vec::new();
+            for url in tier{
+                if !ret.contains(&url) && !new_tier.contains(&url){
+                    new_tier.push(url);
+                }
+            }
+
+            if !new_tier.is_empty(){
+                ret.tiers.push(new_tier);
+            }
+        }
+
+        ret
+    }
+
+    // build the list from the announce and announce-list keys of a torrent, the announce url is only used
+    // as a last resort when the announce-list doesn't already contain it
+    pub fn new(announce_url: &str, announce_list: Option<&Bencode>) -> AnnounceList{
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+
+        if let Some(Bencode::List(list)) = announce_list{
+            for tier in list{
+                match tier{
+                    Bencode::List(e) => tiers.push(e.iter().filter_map(to_url).collect()),
+                    // some torrents use a flat list of urls, treat each one as its own tier
+                    e => tiers.push(to_url(e).into_iter().collect())
+                }
+            }
+        }
+
+        if !announce_url.is_empty(){
+            tiers.push(vec![announce_url.to_string()]);
+        }
+
+        AnnounceList::from_tiers(tiers)
+    }
+
+    pub fn contains(&self, url: &str) -> bool{
+        self.tiers.iter().any(|tier| tier.iter().any(|e| e == url))
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.tiers.is_empty()
+    }
+
+    // every tracker in the order it should be tried
+    pub fn urls(&self) -> Vec<String>{
+        self.tiers.iter().flatten().cloned().collect()
+    }
+
+    // BEP 12 says the trackers within each tier are shuffled when the torrent is loaded
+    pub fn shuffle(&mut self){
+        let mut rng = rand::thread_rng();
+        for tier in self.tiers.iter_mut(){
+            tier.shuffle(&mut rng);
+        }
+    }
+
+    // move a tracker that responded to the front of its tier so it is tried first next time
+    pub fn promote(&mut self, url: &str){
+        for tier in self.tiers.iter_mut(){
+            if let Some(index) = tier.iter().position(|e| e == url){
+                let url = tier.remove(index);
+                tier.insert(0, url);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod announce_list_tests {
+    use crate::announce_list::AnnounceList;
+    use bencode::Bencode;
+
+    fn url(e: &str) -> Bencode{
+        Bencode::ByteString(e.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_tiers(){
+        let list = Bencode::List(vec![
+            Bencode::List(vec![url("udp://a"), url("udp://b"), url("udp://a")]),
+            Bencode::List(vec![url("http://c"), url("udp://b")]),
+            Bencode::List(vec![])
+        ]);
+
+        let mut announce_list = AnnounceList::new("http://c", Some(&list));
+        assert_eq!(announce_list.tiers, vec![vec!["udp://a".to_string(), "udp://b".to_string()], vec!["http://c".to_string()]]);
+
+        announce_list.promote("udp://b");
+        assert_eq!(announce_list.urls(), vec!["udp://b", "udp://a", "http://c"]);
+
+        let announce_list = AnnounceList::new("http://d", None);
+        assert_eq!(announce_list.tiers, vec![vec!["http://d".to_string()]]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::ops::Range;
+
+use bencode::util::ByteString;
+use crate::utils::TorrentError;
+
+// the bencode crate only gives us decoded values, this walks the encoded bytes so the exact
+// bytes of a value can be recovered (ie. the info dict for hashing) even if they are not canonical
+
+// real torrents nest a handful of levels (v2 file trees follow the directory depth), anything deeper
+// is refused instead of recursing until the stack runs out
+const MAX_DEPTH: usize = 256;
+
+fn parse_length(buf: &[u8], pos: usize, terminator: u8) -> Option<(i64, usize)>{
+    let end = pos + buf[pos..].iter().position(|c| *c == terminator)?;
+    let value: i64 = std::str::from_utf8(&buf[pos..end]).ok()?.parse().ok()?;
+    Some((value, end + 1))
+}
+
+// returns the position just after the bencoded value starting at `pos`
+fn skip_value(buf: &[u8], pos: usize, depth: usize) -> Option<usize>{
+    match buf.get(pos)?{
+        b'i' => parse_length(buf, pos + 1, b'e').map(|(_, end)| end),
+        b'l' | b'd' => {
+            if depth >= MAX_DEPTH{
+                return None;
+            }
+
+            let mut pos = pos + 1;
+            while *buf.get(pos)? != b'e'{
+                pos = skip_value(buf, pos, depth + 1)?;
+            }
+            Some(pos + 1)
+        },
+        b'0'..=b'9' => {
+            let (len, start) = parse_length(buf, pos, b':')?;
+            let end = start.checked_add(len as usize)?;
+            if len < 0 || end > buf.len(){
+                return None;
+            }
+            Some(end)
+        },
+        _ => None
+    }
+}
+
+// returns the length of the bencoded value at the start of the buffer
+pub fn value_len(buf: &[u8]) -> Option<usize>{
+    skip_value(buf, 0, 0)
+}
+
+// the key and byte range of every entry of the dictionary in `buf`, in the order they appear
+pub fn dict_entries(buf: &[u8]) -> Result<Vec<(ByteString, Range<usize>)>, TorrentError>{
+    if buf.first() != Some(&b'd') || value_len(buf) != Some(buf.len()){
+        return Err(TorrentError::new(String::from("Expected a bencoded dictionary")));
+    }
+
+    let mut ret: Vec<(ByteString, Range<usize>)> = Vec::new();
+    let mut pos = 1;
+    while buf[pos] != b'e'{
+        let (len, key_start) = match parse_length(buf, pos, b':'){
+            Some(e) => e,
+            _ => return Err(TorrentError::new(String::from("Dictionary key is not a byte string")))
+        };
+
+        let value_start = key_start + len as usize;
+        let value_end = match skip_value(buf, value_start, 1){
+            Some(e) => e,
+            _ => return Err(TorrentError::new(String::from("Malformed dictionary value")))
+        };
+
+        ret.push((ByteString::from_slice(&buf[key_start..value_start]), value_start..value_end));
+        pos = value_end;
+    }
+
+    Ok(ret)
+}
+
+// the raw bytes of a value in a bencoded dictionary
+pub fn dict_value<'a>(buf: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, TorrentError>{
+    let entries = match dict_entries(buf){
+        Ok(e) => e,
+        Err(e) => return Err(e)
+    };
+
+    Ok(entries.into_iter().find(|(k, _)| k.as_slice() == key.as_bytes()).map(|(_, range)| &buf[range]))
+}
+
+#[cfg(test)]
+mod bencode_raw_tests {
+    use crate::bencode_raw::{dict_value, value_len};
+    use crate::torrent_file::TorrentInfo;
+
+    #[test]
+    fn test_info_hash_of_unsorted_info(){
+        // keys of the info dict are out of order, so re-encoding it would give different bytes
+        let info = b"d6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384ee";
+        let mut buf = b"d8:announce14:http://tracker4:info".to_vec();
+        buf.extend_from_slice(info);
+        buf.push(b'e');
+
+        assert_eq!(dict_value(&buf, "info").unwrap(), Some(&info[..]));
+
+        let torrent = TorrentInfo::from_buffer(buf).unwrap();
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(info);
+        assert_eq!(torrent.info_hash, hasher.digest().bytes());
+        assert_eq!(torrent.info_bytes, info.to_vec());
+    }
+
+    #[test]
+    fn test_nesting_limit(){
+        let nested = |depth: usize| -> Vec<u8>{
+            let mut buf = vec![b'l'; depth];
+            buf.extend(vec![b'e'; depth]);
+            buf
+        };
+
+        assert_eq!(value_len(&nested(100)), Some(200));
+        assert_eq!(value_len(&nested(100000)), None);
+        assert!(TorrentInfo::from_buffer(nested(100000)).is_err());
+    }
+}
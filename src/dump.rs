@@ -0,0 +1,140 @@
+use bencode::Bencode;
+
+use crate::bencode_raw;
+use crate::torrent_file::TorrentInfo;
+use crate::utils::TorrentError;
+
+pub fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|e| format!("{:02x}", e)).collect()
+}
+
+fn escape(text: &str) -> String{
+    let mut ret = String::from("\"");
+    for c in text.chars(){
+        match c{
+            '"' => ret += "\\\"",
+            '\\' => ret += "\\\\",
+            '\n' => ret += "\\n",
+            '\r' => ret += "\\r",
+            '\t' => ret += "\\t",
+            c if (c as u32) < 0x20 => ret += &format!("\\u{:04x}", c as u32),
+            c => ret.push(c)
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+// text stays readable, anything else (hashes, piece layers, legacy names) is shown as {"hex": "..."}
+fn byte_string(bytes: &[u8]) -> String{
+    match std::str::from_utf8(bytes){
+        Ok(e) if !e.chars().any(|c| c.is_control()) => escape(e),
+        _ => format!("{{\"hex\": \"{}\"}}", to_hex(bytes))
+    }
+}
+
+fn render(value: &Bencode, indent: usize, out: &mut String){
+    let padding = "  ".repeat(indent + 1);
+    match value{
+        Bencode::Empty => *out += "null",
+        Bencode::Number(e) => *out += &e.to_string(),
+        Bencode::ByteString(e) => *out += &byte_string(e),
+        Bencode::List(list) => {
+            if list.is_empty(){
+                *out += "[]";
+                return;
+            }
+
+            *out += "[\n";
+            for (i, item) in list.iter().enumerate(){
+                *out += &padding;
+                render(item, indent + 1, out);
+                *out += if i + 1 < list.len() { ",\n" } else { "\n" };
+            }
+            *out += &format!("{}]", "  ".repeat(indent));
+        },
+        Bencode::Dict(dict) => {
+            if dict.is_empty(){
+                *out += "{}";
+                return;
+            }
+
+            *out += "{\n";
+            for (i, (key, item)) in dict.iter().enumerate(){
+                // json keys have to be strings, so binary keys (v2 piece layers) become their hex
+                let key = match std::str::from_utf8(key.as_slice()){
+                    Ok(e) if !e.chars().any(|c| c.is_control()) => escape(e),
+                    _ => escape(&to_hex(key.as_slice()))
+                };
+                *out += &format!("{}{}: ", padding, key);
+                render(item, indent + 1, out);
+                *out += if i + 1 < dict.len() { ",\n" } else { "\n" };
+            }
+            *out += &format!("{}}}", "  ".repeat(indent));
+        }
+    }
+}
+
+// every key of the file, including the ones neon doesn't use, after a summary of the parsed torrent. a torrent
+// neon rejects is still shown with the reason instead of the summary, those are the ones that need a look
+pub fn dump(buf: &[u8]) -> Result<String, TorrentError>{
+    // the bencode crate recurses without a limit, so check the nesting before handing it over
+    if bencode_raw::value_len(buf).is_none(){
+        return Err(TorrentError::new(String::from("Unable to parse torrent file")));
+    }
+
+    let metainfo = match bencode::from_buffer(buf){
+        Ok(e) => e,
+        Err(_) => return Err(TorrentError::new(String::from("Unable to parse torrent file")))
+    };
+
+    let mut out = String::from("{\n");
+    match TorrentInfo::from_buffer(buf.to_vec()){
+        Ok(info) => {
+            out += &format!("  \"info_hash\": \"{}\",\n", to_hex(&info.info_hash));
+            out += &format!("  \"info_hash_v2\": {},\n", match info.info_hash_v2{Some(e) => format!("\"{}\"", to_hex(&e)), _ => String::from("null")});
+            out += &format!("  \"private\": {},\n", info.private);
+            out += &format!("  \"num_pieces\": {},\n", info.num_pieces);
+            out += &format!("  \"piece_length\": {},\n", info.piece_byte_size);
+            out += &format!("  \"total_size\": {},\n", info.byte_size);
+            out += &format!("  \"warnings\": [{}],\n", info.validate().iter().map(|e| escape(e)).collect::<Vec<String>>().join(", "));
+        },
+        Err(e) => out += &format!("  \"error\": {},\n", escape(&e.details))
+    };
+    out += "  \"metainfo\": ";
+    render(&metainfo, 1, &mut out);
+    out += "\n}";
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod dump_tests {
+    use crate::dump::dump;
+
+    #[test]
+    fn test_dump_unknown_keys(){
+        let mut buf = b"d8:announce14:http://tracker4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:".to_vec();
+        buf.extend(&[0xab; 20]);
+        buf.extend(b"e9:x-unknownl1:\"ee");
+
+        let out = dump(&buf).unwrap();
+        assert!(out.contains("\"pieces\": {\"hex\": \"abababababababababababababababababababab\"}"));
+        assert!(out.contains("\"x-unknown\": [\n      \"\\\"\"\n    ]"));
+        assert!(out.contains("\"private\": false"));
+    }
+
+    #[test]
+    fn test_dump_invalid_torrent(){
+        // a piece length that isn't a power of two, the keys are shown along with why neon rejects it
+        let mut buf = b"d4:infod6:lengthi5e4:name5:a.txt12:piece lengthi10000e6:pieces20:".to_vec();
+        buf.extend(&[0xab; 20]);
+        buf.extend(b"ee");
+
+        let out = dump(&buf).unwrap();
+        assert!(out.contains("\"error\": "));
+        assert!(out.contains("\"piece length\": 10000"));
+        assert!(!out.contains("\"info_hash\""));
+        assert!(dump(b"d4:info").is_err());
+    }
+}
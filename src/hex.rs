@@ -0,0 +1,118 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{DeserializeOwned, Error};
+use std::convert::TryInto;
+
+use crate::dump::to_hex;
+
+// byte strings are written as hex with `#[serde(with = "crate::hex")]`, json would otherwise hold them
+// as arrays of numbers
+pub trait HexBytes: Sized{
+    type Repr: Serialize + DeserializeOwned;
+
+    fn to_repr(&self) -> Self::Repr;
+    fn from_repr(repr: Self::Repr) -> Option<Self>;
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>>{
+    if text.len() % 2 != 0{
+        return None;
+    }
+
+    let mut ret = Vec::new();
+    for i in (0..text.len()).step_by(2){
+        match text.get(i..i + 2).and_then(|e| u8::from_str_radix(e, 16).ok()){
+            Some(e) => ret.push(e),
+            _ => return None
+        };
+    }
+    Some(ret)
+}
+
+impl HexBytes for Vec<u8>{
+    type Repr = String;
+
+    fn to_repr(&self) -> String{
+        to_hex(self)
+    }
+
+    fn from_repr(repr: String) -> Option<Vec<u8>>{
+        from_hex(&repr)
+    }
+}
+
+impl<const N: usize> HexBytes for [u8; N]{
+    type Repr = String;
+
+    fn to_repr(&self) -> String{
+        to_hex(self)
+    }
+
+    fn from_repr(repr: String) -> Option<[u8; N]>{
+        from_hex(&repr).and_then(|e| e.try_into().ok())
+    }
+}
+
+impl<T: HexBytes> HexBytes for Vec<T>{
+    type Repr = Vec<T::Repr>;
+
+    fn to_repr(&self) -> Vec<T::Repr>{
+        self.iter().map(|e| e.to_repr()).collect()
+    }
+
+    fn from_repr(repr: Vec<T::Repr>) -> Option<Vec<T>>{
+        repr.into_iter().map(T::from_repr).collect()
+    }
+}
+
+impl<T: HexBytes> HexBytes for Option<T>{
+    type Repr = Option<T::Repr>;
+
+    fn to_repr(&self) -> Option<T::Repr>{
+        self.as_ref().map(|e| e.to_repr())
+    }
+
+    fn from_repr(repr: Option<T::Repr>) -> Option<Option<T>>{
+        match repr{
+            Some(e) => T::from_repr(e).map(Some),
+            _ => Some(None)
+        }
+    }
+}
+
+pub fn serialize<T: HexBytes, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error>{
+    value.to_repr().serialize(serializer)
+}
+
+pub fn deserialize<'de, T: HexBytes, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error>{
+    let repr = match T::Repr::deserialize(deserializer){
+        Ok(e) => e,
+        Err(e) => return Err(e)
+    };
+
+    match T::from_repr(repr){
+        Some(e) => Ok(e),
+        _ => Err(D::Error::custom("invalid hex"))
+    }
+}
+
+#[cfg(test)]
+mod hex_tests {
+    use crate::torrent_builder::TestDir;
+    use crate::torrent_file::TorrentInfo;
+
+    #[test]
+    fn test_hex_fields(){
+        let dir = TestDir::new("hex", &[("a.bin", vec![7u8; 1000])]);
+        let info = dir.torrent("a.bin", |e| e.announce("http://tracker.example.com/announce"));
+
+        // hashes and paths are hex strings and the raw copies of the file are left out
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(&format!("\"info_hash\":\"{}\"", crate::dump::to_hex(&info.info_hash))));
+        assert!(json.contains("\"raw_path\":[\"612e62696e\"]"));
+        assert!(!json.contains("info_bytes") && !json.contains("raw_entries"));
+
+        let parsed: TorrentInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.hashes, info.hashes);
+        assert_eq!(parsed.files[0].raw_path, info.files[0].raw_path);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::torrent_file::TorrentInfo;
+use crate::tracker::encode_param;
+use crate::piece_source;
+use crate::piece_source::FetchError;
+use crate::utils::{TorrentChannel, TorrentError, TorrentEvent};
+
+const REQUEST_TIMEOUT: u64 = 30;
+// used when a busy server doesn't say how long to wait
+const DEFAULT_RETRY_SECS: u64 = 30;
+const MAX_RETRY_SECS: u64 = 3600;
+
+// a BEP 17 http seed, a script that serves whole pieces by index instead of files
+#[derive(Debug, Clone)]
+pub struct HttpSeed{
+    pub url: String,
+    info_hash: [u8; 20],
+    piece_byte_size: u64,
+    pub retry_at: Option<Instant>
+}
+
+impl HttpSeed{
+    pub fn new(url: &str, info: &TorrentInfo) -> HttpSeed{
+        HttpSeed{
+            url: url.to_string(),
+            info_hash: info.info_hash,
+            piece_byte_size: info.piece_byte_size,
+            retry_at: None
+        }
+    }
+
+    fn piece_url(&self, index: usize, size: u64) -> String{
+        let separator = if self.url.contains('?') { "&" } else { "?" };
+        format!("{}{}info_hash={}&piece={}&ranges=0-{}", self.url, separator, encode_param(&self.info_hash), index, size - 1)
+    }
+
+    // a busy server answers with 503 and the number of seconds to wait as the body, or in a Retry-After header
+    fn retry_after(response: &minreq::Response) -> Duration{
+        let secs: u64 = match response.headers.get("retry-after"){
+            Some(e) => e.trim().parse().unwrap_or(DEFAULT_RETRY_SECS),
+            _ => match response.as_str(){
+                Ok(e) => e.trim().parse().unwrap_or(DEFAULT_RETRY_SECS),
+                _ => DEFAULT_RETRY_SECS
+            }
+        };
+
+        Duration::from_secs(secs.min(MAX_RETRY_SECS))
+    }
+
+    pub fn fetch_piece(&mut self, index: usize, size: u64) -> Result<Vec<u8>, TorrentError>{
+        let response = match minreq::get(self.piece_url(index, size)).with_timeout(REQUEST_TIMEOUT).send(){
+            Ok(e) => e,
+            Err(_) => return Err(TorrentError::new(format!("Unable to get a response from http seed {}", self.url)))
+        };
+
+        match response.status_code{
+            200 => (),
+            503 => {
+                let wait = HttpSeed::retry_after(&response);
+                self.retry_at = Some(Instant::now() + wait);
+                return Err(TorrentError::new(format!("Http seed {} is busy, retrying in {} seconds", self.url, wait.as_secs())));
+            },
+            e => return Err(TorrentError::new(format!("Http seed {} responded with status {}", self.url, e)))
+        };
+
+        let data = response.into_bytes();
+        if data.len() as u64 != size{
+            return Err(TorrentError::new(format!("Http seed {} sent {} bytes for piece {} but {} were requested", self.url, data.len(), index, size)));
+        }
+
+        Ok(data)
+    }
+
+    pub fn start_download(mut seed: HttpSeed, channel: TorrentChannel<TorrentEvent>, output: Arc<Mutex<Vec<u8>>>) -> JoinHandle<()>{
+        let name = format!("http seed {}", seed.url);
+        let piece_byte_size = seed.piece_byte_size;
+        piece_source::start_download(name, piece_byte_size, move |index, size| match seed.fetch_piece(index, size){
+            Ok(e) => Ok(e),
+            // being told to wait is not a failure
+            Err(e) => match seed.retry_at.take(){
+                Some(retry_at) => Err(FetchError::Busy(e, retry_at)),
+                None => Err(FetchError::Failed(e))
+            }
+        }, channel, output)
+    }
+}
+
+#[cfg(test)]
+mod http_seed_tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::torrent_builder::TestDir;
+    use crate::http_seed::HttpSeed;
+
+    #[test]
+    fn test_busy_then_piece(){
+        let content: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let dir = TestDir::new("http-seed", &[("a.bin", content.clone())]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let served = content.clone();
+        thread::spawn(move ||{
+            for (i, stream) in listener.incoming().enumerate(){
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(stream.try_clone().unwrap()).read_line(&mut request).unwrap();
+
+                let response = if i == 0{
+                    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\nConnection: close\r\n\r\n60".to_vec()
+                } else {
+                    assert!(request.contains("piece=1&ranges=0-7231"));
+                    let mut resp = b"HTTP/1.1 200 OK\r\nContent-Length: 7232\r\nConnection: close\r\n\r\n".to_vec();
+                    resp.extend(&served[32768..]);
+                    resp
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        let info = dir.torrent("a.bin", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce"));
+        let mut seed = HttpSeed::new(&format!("http://127.0.0.1:{}/seed.php", port), &info);
+
+        assert!(seed.fetch_piece(1, 7232).is_err());
+        assert!(seed.retry_at.unwrap() > Instant::now() + Duration::from_secs(50));
+
+        let piece = seed.fetch_piece(1, 7232).unwrap();
+        assert!(info.verify_piece(1, &piece));
+    }
+}
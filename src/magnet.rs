@@ -0,0 +1,227 @@
+extern crate base32;
+
+use std::convert::TryInto;
+
+use percent_encoding::percent_decode_str;
+use crate::utils::TorrentError;
+use crate::torrent_v2::{HashV2, truncate_hash};
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+// v2 info hashes are multihashes, 0x12 for SHA-256 followed by its length of 0x20 bytes
+const BTMH_PREFIX: &str = "urn:btmh:1220";
+
+#[derive(Debug, Clone)]
+pub struct MagnetLink{
+    // the v1 info hash, or the truncated v2 hash when the link only has a btmh
+    pub info_hash: [u8; 20],
+    pub info_hash_v2: Option<HashV2>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub select_only: Vec<usize>
+}
+
+fn decode_component(value: &str) -> String{
+    // '+' is commonly used in place of a space in query strings
+    percent_decode_str(&value.replace("+", " ")).decode_utf8_lossy().to_string()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>>{
+    if hex.len() % 2 != 0{
+        return None;
+    }
+
+    let mut ret = Vec::with_capacity(hex.len() / 2);
+    for i in 0..(hex.len() / 2){
+        ret.push(match u8::from_str_radix(&hex[i*2..(i+1)*2], 16){
+            Ok(e) => e,
+            _ => return None
+        });
+    }
+
+    Some(ret)
+}
+
+fn has_prefix(value: &str, prefix: &str) -> bool{
+    value.len() >= prefix.len() && value[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+// the full SHA-256 info hash of a v2 torrent from a urn:btmh:1220<hex> topic
+fn parse_info_hash_v2(exact_topic: &str) -> Result<Option<HashV2>, TorrentError>{
+    if !has_prefix(exact_topic, BTMH_PREFIX){
+        return Ok(None);
+    }
+
+    let encoded = &exact_topic[BTMH_PREFIX.len()..];
+    match decode_hex(encoded){
+        Some(e) => match e.as_slice().try_into(){
+            Ok(hash) => Ok(Some(hash)),
+            _ => Err(TorrentError::new(format!("Invalid v2 info hash length in magnet link: {}", encoded)))
+        },
+        _ => Err(TorrentError::new(format!("Unable to decode magnet v2 info hash: {}", encoded)))
+    }
+}
+
+fn parse_info_hash(exact_topic: &str) -> Result<Option<[u8; 20]>, TorrentError>{
+    // ignore topics we do not understand as long as a btih or btmh is also supplied
+    if !has_prefix(exact_topic, BTIH_PREFIX){
+        return Ok(None);
+    }
+
+    let encoded = &exact_topic[BTIH_PREFIX.len()..];
+    let decoded = match encoded.len(){
+        40 => decode_hex(encoded),
+        32 => base32::decode(base32::Alphabet::RFC4648 { padding: false }, &encoded.to_uppercase()),
+        _ => None
+    };
+
+    match decoded{
+        Some(e) => match e.as_slice().try_into(){
+            Ok(hash) => Ok(Some(hash)),
+            _ => Err(TorrentError::new(format!("Invalid info hash length in magnet link: {}", encoded)))
+        },
+        _ => Err(TorrentError::new(format!("Unable to decode magnet info hash: {}", encoded)))
+    }
+}
+
+// parses a select only value of the form "0,2,4,6-8" into a list of file indices
+fn parse_select_only(value: &str) -> Result<Vec<usize>, TorrentError>{
+    let mut ret: Vec<usize> = Vec::new();
+    for entry in value.split(',').filter(|e| !e.is_empty()){
+        let bounds: Vec<&str> = entry.splitn(2, '-').collect();
+        let start: usize = match bounds[0].parse(){
+            Ok(e) => e,
+            _ => return Err(TorrentError::new(format!("Invalid file index in magnet link: {}", entry)))
+        };
+
+        let end: usize = match bounds.get(1){
+            Some(e) => match e.parse(){
+                Ok(e) => e,
+                _ => return Err(TorrentError::new(format!("Invalid file range in magnet link: {}", entry)))
+            },
+            _ => start
+        };
+
+        if end < start{
+            return Err(TorrentError::new(format!("Invalid file range in magnet link: {}", entry)));
+        }
+
+        ret.extend(start..=end);
+    }
+
+    Ok(ret)
+}
+
+impl MagnetLink{
+    pub fn is_magnet(uri: &str) -> bool{
+        uri.starts_with(MAGNET_PREFIX)
+    }
+
+    pub fn parse(uri: &str) -> Result<MagnetLink, TorrentError>{
+        if !MagnetLink::is_magnet(uri){
+            return Err(TorrentError::new(String::from("Magnet link must start with magnet:?")));
+        }
+
+        let mut info_hash: Option<[u8; 20]> = None;
+        let mut ret = MagnetLink{
+            info_hash: [0; 20],
+            info_hash_v2: None,
+            display_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            web_seeds: Vec::new(),
+            select_only: Vec::new()
+        };
+
+        for param in uri[MAGNET_PREFIX.len()..].split('&').filter(|e| !e.is_empty()){
+            let (key, value) = match param.find('='){
+                Some(i) => (&param[..i], decode_component(&param[i+1..])),
+                _ => continue
+            };
+
+            // keys can be suffixed with an index (ie. tr.1) when repeated
+            let key = match key.find('.'){
+                Some(i) if key[i+1..].chars().all(|c| c.is_ascii_digit()) => &key[..i],
+                _ => key
+            };
+
+            match key{
+                "xt" => match (parse_info_hash(&value), parse_info_hash_v2(&value)){
+                    (Ok(Some(hash)), _) => info_hash = Some(hash),
+                    (_, Ok(Some(hash))) => ret.info_hash_v2 = Some(hash),
+                    (Err(e), _) | (_, Err(e)) => return Err(e),
+                    _ => ()
+                },
+                "dn" => ret.display_name = Some(value),
+                "tr" => if !ret.trackers.contains(&value){ ret.trackers.push(value) },
+                "x.pe" => ret.peers.push(value),
+                "ws" => ret.web_seeds.push(value),
+                "so" => ret.select_only = match parse_select_only(&value){
+                    Ok(e) => e,
+                    Err(e) => return Err(e)
+                },
+                _ => ()
+            }
+        }
+
+        // a hybrid link has both, a v2 only torrent is known to peers and trackers by its truncated v2 hash
+        ret.info_hash = match (info_hash, ret.info_hash_v2){
+            (Some(e), _) => e,
+            (_, Some(e)) => truncate_hash(&e),
+            _ => return Err(TorrentError::new(String::from("Magnet link does not contain a BitTorrent info hash")))
+        };
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod magnet_tests {
+    use crate::magnet::MagnetLink;
+    use crate::torrent_v2::truncate_hash;
+
+    #[test]
+    fn test_hex_and_base32_hashes(){
+        let hex = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+        let base32 = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+
+        assert_eq!(hex.info_hash, base32.info_hash);
+        assert_eq!(hex.info_hash[0], 0xc1);
+    }
+
+    #[test]
+    fn test_parse_params(){
+        let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some+File%20Name\
+            &tr=udp%3A%2F%2Ftracker.example.com%3A80&tr.1=http%3A%2F%2Fexample.org%2Fannounce&x.pe=10.0.0.1:6881\
+            &ws=http%3A%2F%2Fmirror.example.com%2Ffile&so=0,2,4-6").unwrap();
+
+        assert_eq!(magnet.display_name.unwrap(), "Some File Name");
+        assert_eq!(magnet.trackers, vec!["udp://tracker.example.com:80", "http://example.org/announce"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881"]);
+        assert_eq!(magnet.web_seeds, vec!["http://mirror.example.com/file"]);
+        assert_eq!(magnet.select_only, vec![0, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_v2_hash(){
+        let v2 = "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btmh:{}", v2)).unwrap();
+        assert_eq!(magnet.info_hash_v2.unwrap()[0], 0xca);
+        assert_eq!(magnet.info_hash, truncate_hash(&magnet.info_hash_v2.unwrap()));
+
+        // a hybrid link keeps its v1 hash for the handshake
+        let hybrid = MagnetLink::parse(&format!("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&xt=urn:btmh:{}", v2)).unwrap();
+        assert_eq!(hybrid.info_hash[0], 0xc1);
+        assert!(hybrid.info_hash_v2.is_some());
+
+        assert!(MagnetLink::parse("magnet:?xt=urn:btmh:1220caf1").is_err());
+    }
+
+    #[test]
+    fn test_missing_hash(){
+        assert!(MagnetLink::parse("magnet:?dn=test").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+    }
+}
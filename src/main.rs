@@ -1,27 +1,267 @@
+mod announce_list;
+mod bencode_raw;
 mod torrent_file;
+mod torrent_v2;
+mod torrent_builder;
+mod magnet;
+mod metadata;
 mod peers;
 mod torrent;
 mod tracker;
+mod web_seed;
+mod http_seed;
+mod piece_source;
+mod sanitize;
+mod storage;
+mod dump;
+#[cfg(feature = "serde")]
+mod hex;
 mod utils;
 
 use crate::torrent_file::TorrentInfo;
+use crate::magnet::MagnetLink;
+use crate::torrent_builder::{TorrentBuilder, TorrentVersion};
 use colored::Colorize;
 use std::env;
 use crate::tracker::Tracker;
 
+fn create(arguments: &[String]){
+    if arguments.len() < 4{
+        eprintln!("Usage: ./neon create <file or directory> <output torrent> [--announce <url>] [--comment <text>] [--piece-size <bytes>] [--threads <count>] [--web-seed <url>] [--private] [--v2 | --hybrid]");
+        return;
+    }
+
+    let mut builder = TorrentBuilder::new(&arguments[2]);
+    let mut i = 4;
+    while i < arguments.len(){
+        let value = arguments.get(i + 1).cloned().unwrap_or_default();
+        match arguments[i].as_str(){
+            "--announce" => {builder = builder.announce(&value); i += 1},
+            "--comment" => {builder = builder.comment(&value); i += 1},
+            "--web-seed" => {builder = builder.web_seed(&value); i += 1},
+            "--piece-size" => {
+                match value.parse(){
+                    Ok(e) => builder = builder.piece_size(e),
+                    Err(_) => {eprintln!("[{}] Invalid piece size {}", "X".red(), value); return}
+                };
+                i += 1
+            },
+            "--threads" => {
+                match value.parse(){
+                    Ok(e) => builder = builder.threads(e),
+                    Err(_) => {eprintln!("[{}] Invalid number of threads {}", "X".red(), value); return}
+                };
+                i += 1
+            },
+            "--private" => builder = builder.private(true),
+            "--v2" => builder = builder.version(TorrentVersion::V2),
+            "--hybrid" => builder = builder.version(TorrentVersion::Hybrid),
+            e => eprintln!("[{}] Ignoring unknown option {}", "-".yellow(), e)
+        }
+        i += 1;
+    }
+
+    match builder.write(&arguments[3]){
+        Ok(_) => println!("[{}] Created {}", "*".green(), arguments[3]),
+        Err(e) => eprintln!("{}", e.details)
+    }
+}
+
+fn edit(arguments: &[String]){
+    if arguments.len() < 4{
+        eprintln!("Usage: ./neon edit <torrent> <output torrent> [--announce <url>] [--comment <text>] [--created-by <text>] [--creation-date <unix time>] [--web-seed <url>] [--http-seed <url>]");
+        return;
+    }
+
+    let mut info = match TorrentInfo::from_filename(arguments[2].clone()){
+        Ok(e) => e,
+        Err(e) => {eprintln!("{}", e.details); return}
+    };
+
+    // every --announce is its own tier and together they replace the existing trackers
+    let mut tiers: Vec<Vec<String>> = Vec::new();
+    let mut i = 4;
+    while i < arguments.len(){
+        let value = arguments.get(i + 1).cloned().unwrap_or_default();
+        match arguments[i].as_str(){
+            "--announce" => tiers.push(vec![value]),
+            "--comment" => info.set_comment(&value),
+            "--created-by" => info.set_creator(&value),
+            "--creation-date" => {
+                match value.parse(){
+                    Ok(e) => info.set_creation_date(e),
+                    Err(_) => {eprintln!("[{}] Invalid creation date {}", "X".red(), value); return}
+                }
+            },
+            "--web-seed" => info.add_web_seed(&value),
+            "--http-seed" => {let mut seeds = info.http_seeds.clone(); seeds.push(value); info.set_http_seeds(seeds)},
+            e => {eprintln!("[{}] Ignoring unknown option {}", "-".yellow(), e); i += 1; continue}
+        }
+        i += 2;
+    }
+
+    if !tiers.is_empty(){
+        info.set_trackers(tiers);
+    }
+
+    match info.write(&arguments[3]){
+        Ok(_) => println!("[{}] Wrote {}, info hash unchanged", "*".green(), arguments[3]),
+        Err(e) => eprintln!("{}", e.details)
+    }
+}
+
+fn check(arguments: &[String]){
+    if arguments.len() < 3{
+        eprintln!("Usage: ./neon check <torrent>");
+        return;
+    }
+
+    let info = match TorrentInfo::from_filename(arguments[2].clone()){
+        Ok(e) => e,
+        Err(e) => {
+            match e.reason{
+                Some(reason) => println!("[{}] {} was rejected: {}", "X".red(), arguments[2], reason),
+                _ => eprintln!("{}", e.details)
+            };
+            std::process::exit(1);
+        }
+    };
+
+    let warnings = info.validate();
+    for warning in warnings.iter(){
+        println!("[{}] {}", "-".yellow(), warning);
+    }
+    println!("[{}] {} is valid with {} warnings", "*".green(), arguments[2], warnings.len());
+}
+
+// print the swarm of the torrent as seen by each of its trackers
+fn scrape(arguments: &[String]){
+    if arguments.len() < 3{
+        eprintln!("Usage: ./neon scrape <torrent | magnet link>");
+        return;
+    }
+
+    let info = if MagnetLink::is_magnet(&arguments[2]){
+        match MagnetLink::parse(&arguments[2]){
+            Ok(e) => TorrentInfo::from_magnet(&e),
+            Err(e) => {eprintln!("{}", e.details); std::process::exit(1)}
+        }
+    }
+    else{
+        match TorrentInfo::from_filename(arguments[2].clone()){
+            Ok(e) => e,
+            Err(e) => {eprintln!("{}", e.details); std::process::exit(1)}
+        }
+    };
+
+    let info_hashes = info.info_hashes();
+    for url in info.announce_list.urls(){
+        match Tracker::scrape_tracker(&url, &info_hashes){
+            Ok(stats) if stats.is_empty() => println!("[{}] {} doesn't know this torrent", "-".yellow(), url),
+            Ok(stats) => for (info_hash, e) in stats{
+                println!("[{}] {} ({}): {} seeders, {} leechers, {} downloaded", "*".green(), url, dump::to_hex(&info_hash), e.seeders, e.leechers, e.downloaded);
+            },
+            Err(e) => eprintln!("{}", e.details)
+        };
+    }
+}
+
+fn dump(arguments: &[String]){
+    if arguments.len() < 3{
+        eprintln!("Usage: ./neon dump <torrent> [--parsed]");
+        return;
+    }
+
+    let buf = match std::fs::read(&arguments[2]){
+        Ok(e) => e,
+        Err(e) => {eprintln!("[{}] Could not read {}: {}", "X".red(), arguments[2], e); return}
+    };
+
+    if arguments.iter().any(|e| e == "--parsed") && dump_parsed(&buf){
+        return;
+    }
+
+    match dump::dump(&buf){
+        Ok(e) => println!("{}", e),
+        Err(e) => eprintln!("{}", e.details)
+    }
+}
+
+// false when there is no parsed torrent to show, the keys of the file are shown instead
+#[cfg(feature = "serde")]
+fn dump_parsed(buf: &[u8]) -> bool{
+    match TorrentInfo::from_buffer(buf.to_vec()){
+        Ok(e) => {println!("{}", serde_json::to_string_pretty(&e).unwrap()); true},
+        Err(e) => {eprintln!("{}", e.details); false}
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn dump_parsed(_buf: &[u8]) -> bool{
+    eprintln!("[{}] Neon was built without the serde feature", "X".red());
+    true
+}
+
 fn main(){
 
     let arguments: Vec<String> = env::args().collect();
 
+    if arguments.len() > 1 && arguments[1] == "create"{
+        create(&arguments);
+        return;
+    }
+
+    if arguments.len() > 1 && arguments[1] == "dump"{
+        dump(&arguments);
+        return;
+    }
+
+    if arguments.len() > 1 && arguments[1] == "check"{
+        check(&arguments);
+        return;
+    }
+
+    if arguments.len() > 1 && arguments[1] == "scrape"{
+        scrape(&arguments);
+        return;
+    }
+
+    if arguments.len() > 1 && arguments[1] == "edit"{
+        edit(&arguments);
+        return;
+    }
+
     if arguments.len() < 3{
-        eprintln!("Usage: ./neon <torrent name> <output name>")
+        eprintln!("Usage: ./neon <torrent name | magnet link> <output name> [--all-trackers]");
+        eprintln!("       ./neon create <file or directory> <output torrent> [options]");
+        eprintln!("       ./neon edit <torrent> <output torrent> [options]");
+        eprintln!("       ./neon check <torrent>");
+        eprintln!("       ./neon scrape <torrent | magnet link>");
+        eprintln!("       ./neon dump <torrent> [--parsed]")
     }
 
-    let info = TorrentInfo::from_filename(arguments[1].clone()).unwrap();
+    let mut initial_peers: Vec<String> = Vec::new();
+    let mut select_only: Vec<usize> = Vec::new();
+    let info = if MagnetLink::is_magnet(&arguments[1]){
+        let magnet = MagnetLink::parse(&arguments[1]).unwrap();
+        initial_peers = magnet.peers.clone();
+        select_only = magnet.select_only.clone();
+        println!("[{}] Parsed magnet link, metadata will be fetched from peers", "*".green());
+        TorrentInfo::from_magnet(&magnet)
+    }
+    else{
+        let info = TorrentInfo::from_filename(arguments[1].clone()).unwrap();
+        for warning in info.validate(){
+            println!("[{}] {}", "-".yellow(), warning);
+        }
+        println!("[{}] Parsed torrent info{}, see ./neon dump for the details", "*".green(), if info.private { " (private)" } else { "" });
+        info
+    };
 
-    println!("Num Pieces: {}, Piece Size: {}, Num Bytes: {}, Good: {}", info.num_pieces, info.piece_byte_size, info.byte_size, info.num_pieces * info.piece_byte_size as usize == info.byte_size as usize);
     println!("Info Hash: {}", base64::encode(info.info_hash));
-    println!("[{}] Parsed torrent info", "*".green());
     let torrent = torrent::Torrent::new(info);
+    torrent.lock().unwrap().initial_peers = initial_peers;
+    torrent.lock().unwrap().select_only = select_only;
+    torrent.lock().unwrap().announce_to_all = arguments[3..].iter().any(|e| e == "--all-trackers");
     torrent.lock().unwrap().download(&arguments[2]);
 }
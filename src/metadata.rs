@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread;
+
+use bencode::{Bencode, FromBencode};
+use bencode::util::ByteString;
+use crossbeam_channel::{unbounded, Sender, Receiver};
+use crossbeam_queue::ArrayQueue;
+
+use crate::bencode_raw;
+use crate::peers::Peer;
+use crate::torrent_file::TorrentInfo;
+use crate::utils::{TorrentChannel, TorrentError, TorrentEvent};
+
+// id of the extension handshake message and the id we tell peers to use for ut_metadata
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+pub const UT_METADATA_ID: u8 = 1;
+
+pub const METADATA_PIECE_SIZE: usize = 16384;
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const METADATA_REQUEST: i64 = 0;
+const METADATA_DATA: i64 = 1;
+const METADATA_REJECT: i64 = 2;
+
+fn get_number(dict: &BTreeMap<ByteString, Bencode>, key: &str) -> Option<i64>{
+    match dict.get(&ByteString::from_str(key)){
+        Some(e) => FromBencode::from_bencode(e).ok(),
+        _ => None
+    }
+}
+
+// payload for our extension handshake, advertising ut_metadata support
+pub fn handshake_payload() -> Vec<u8>{
+    let mut extensions: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+    extensions.insert(ByteString::from_str("ut_metadata"), Bencode::Number(UT_METADATA_ID as i64));
+
+    let mut dict: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+    dict.insert(ByteString::from_str("m"), Bencode::Dict(extensions));
+
+    let mut payload = vec![EXTENDED_HANDSHAKE_ID];
+    payload.extend(Bencode::Dict(dict).to_bytes().unwrap());
+    payload
+}
+
+// returns the id the peer wants us to use for ut_metadata and the total size of the metadata
+pub fn parse_handshake(payload: &[u8]) -> Result<(u8, usize), TorrentError>{
+    // checked first since the bencode crate recurses without a limit
+    if bencode_raw::value_len(payload).is_none(){
+        return Err(TorrentError::new(String::from("Malformed extension handshake")));
+    }
+
+    let dict: BTreeMap<ByteString, Bencode> = match bencode::from_buffer(payload){
+        Ok(Bencode::Dict(e)) => e,
+        _ => return Err(TorrentError::new(String::from("Malformed extension handshake")))
+    };
+
+    let extensions: &BTreeMap<ByteString, Bencode> = match dict.get(&ByteString::from_str("m")){
+        Some(Bencode::Dict(e)) => e,
+        _ => return Err(TorrentError::new(String::from("Extension handshake is missing the message map")))
+    };
+
+    let remote_id = match get_number(extensions, "ut_metadata"){
+        Some(e) if e > 0 && e < 256 => e as u8,
+        _ => return Err(TorrentError::new(String::from("Peer does not support ut_metadata")))
+    };
+
+    let size = match get_number(&dict, "metadata_size"){
+        Some(e) if e > 0 && e as usize <= MAX_METADATA_SIZE => e as usize,
+        _ => return Err(TorrentError::new(String::from("Peer sent an invalid metadata size")))
+    };
+
+    Ok((remote_id, size))
+}
+
+pub fn request_payload(remote_id: u8, piece: usize) -> Vec<u8>{
+    let mut dict: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+    dict.insert(ByteString::from_str("msg_type"), Bencode::Number(METADATA_REQUEST));
+    dict.insert(ByteString::from_str("piece"), Bencode::Number(piece as i64));
+
+    let mut payload = vec![remote_id];
+    payload.extend(Bencode::Dict(dict).to_bytes().unwrap());
+    payload
+}
+
+// a data message is a bencoded dictionary immediately followed by the piece of metadata
+pub fn parse_data(payload: &[u8]) -> Result<(usize, Vec<u8>), TorrentError>{
+    let dict_len = match bencode_raw::value_len(payload){
+        Some(e) => e,
+        _ => return Err(TorrentError::new(String::from("Malformed metadata message")))
+    };
+
+    let dict: BTreeMap<ByteString, Bencode> = match bencode::from_buffer(&payload[..dict_len]){
+        Ok(Bencode::Dict(e)) => e,
+        _ => return Err(TorrentError::new(String::from("Malformed metadata message")))
+    };
+
+    let piece = match get_number(&dict, "piece"){
+        Some(e) if e >= 0 => e as usize,
+        _ => return Err(TorrentError::new(String::from("Metadata message is missing the piece index")))
+    };
+
+    match get_number(&dict, "msg_type"){
+        Some(METADATA_DATA) => Ok((piece, Vec::from(&payload[dict_len..]))),
+        Some(METADATA_REJECT) => Err(TorrentError::new(format!("Peer rejected request for metadata piece {}", piece))),
+        _ => Err(TorrentError::new(String::from("Unexpected metadata message type")))
+    }
+}
+
+// ask every peer for the metadata at once and take the first copy that hashes to the info hash
+pub fn fetch_from_peers(peers: &[&Peer], info: &TorrentInfo, my_peer_id: &String) -> Result<Vec<u8>, TorrentError>{
+    let (sender, receiver): (Sender<Result<Vec<u8>, TorrentError>>, Receiver<Result<Vec<u8>, TorrentError>>) = unbounded();
+
+    for peer in peers{
+        let mut peer = Peer::new(peer.ip_addr.clone(), peer.port, peer.expected_peer_id.clone(), my_peer_id.clone(), peer.info_hash, 0, 0);
+        let sender = sender.clone();
+        thread::spawn(move ||{
+            let (event_sender, event_receiver) = unbounded();
+            let mut channel: TorrentChannel<TorrentEvent> = TorrentChannel::new(Arc::new(ArrayQueue::new(1)), event_sender, event_receiver);
+            sender.send(peer.fetch_metadata(&mut channel)).unwrap_or(());
+        });
+    }
+
+    // only the peer threads hold senders now, so recv fails once they have all finished
+    drop(sender);
+
+    for _ in 0..peers.len(){
+        let metadata = match receiver.recv(){
+            Ok(Ok(e)) => e,
+            _ => continue
+        };
+
+        if info.matches_metadata(&metadata){
+            return Ok(metadata);
+        }
+    }
+
+    Err(TorrentError::new(String::from("Unable to fetch the torrent metadata from any peer")))
+}
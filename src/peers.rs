@@ -1,4 +1,4 @@
-use std::net::{TcpStream, SocketAddr, Shutdown};
+use std::net::{TcpStream, SocketAddr, Shutdown, IpAddr, ToSocketAddrs};
 use colored::Colorize;
 use std::io::{Read, Write};
 use std::time::Duration;
@@ -6,11 +6,14 @@ use std::{thread, io};
 use std::thread::JoinHandle;
 use bit_vec::BitVec;
 use crate::utils::{TorrentChannel, TorrentError, TorrentEvent, TorrentEventType, u32_to_bytes, bytes_to_u32};
+use crate::metadata;
 use std::sync::{Arc, Mutex};
 use std::convert::TryInto;
 use stopwatch::Stopwatch;
 
 const HANDSHAKE_MSG: &str = "BitTorrent protocol";
+// byte 5 bit 0x10 advertises support for the extension protocol (BEP 10)
+const HANDSHAKE_RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
 const CONNECTION_TIMEOUT: f32 = 3.0;
 const WRITE_TIMEOUT: f32 = 2.0;
 const READ_TIMEOUT: f32 = 15.0;
@@ -29,6 +32,7 @@ enum RequestType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
     Unknown = -1
 }
 
@@ -44,6 +48,7 @@ impl From<u8> for RequestType{
             6 => RequestType::Request,
             7 => RequestType::Piece,
             8 => RequestType::Cancel,
+            20 => RequestType::Extended,
             _ => RequestType::Unknown
         }
     }
@@ -59,11 +64,13 @@ macro_rules! thread_println {
 pub struct Peer{
     pub ip_addr: String,
     pub port: u16,
-    pub tracker_id: Option<Vec<u8>>,
+    // the peer id a tracker reported for this address, checked against the one in the handshake
+    pub expected_peer_id: Option<Vec<u8>>,
     can_request: bool,
     is_choked: bool,
     tcp_stream: Option<TcpStream>,
-    info_hash: [u8; 20],
+    pub info_hash: [u8; 20],
+    accepted_info_hashes: Vec<[u8; 20]>,
     peer_id: String,
     bitfield: BitVec,
     backlog: u32,
@@ -76,19 +83,21 @@ pub struct Peer{
     average: (u128, usize),
     download_speed_kb: f32,
     max_backlog: u32,
-    is_active: bool
+    is_active: bool,
+    supports_extensions: bool
 }
 
 impl Peer{
-    pub fn new(ip_addr: String, port: u16, tracker_id: Option<Vec<u8>>, my_peer_id: String, info_hash: [u8; 20], num_pieces: usize, piece_size: usize) -> Peer{
+    pub fn new(ip_addr: String, port: u16, expected_peer_id: Option<Vec<u8>>, my_peer_id: String, info_hash: [u8; 20], num_pieces: usize, piece_size: usize) -> Peer{
         Peer{
             ip_addr,
             port,
-            tracker_id,
+            expected_peer_id,
             is_choked: true,
             can_request: false,
             tcp_stream: None,
             info_hash,
+            accepted_info_hashes: vec![info_hash],
             peer_id: my_peer_id,
             bitfield: BitVec::from_elem(num_pieces, false),
             backlog: 0,
@@ -101,7 +110,15 @@ impl Peer{
             average: (0, 0),
             download_speed_kb: 0.0,
             max_backlog: 5,
-            is_active: false
+            is_active: false,
+            supports_extensions: false
+        }
+    }
+
+    // a peer may answer a handshake for a hybrid torrent with either of the torrent's info hashes
+    pub fn accept_info_hash(&mut self, info_hash: [u8; 20]){
+        if !self.accepted_info_hashes.contains(&info_hash){
+            self.accepted_info_hashes.push(info_hash);
         }
     }
 
@@ -202,12 +219,30 @@ impl Peer{
         Ok(())
     }
 
+    // peers are given as IPv4 or IPv6 addresses, IPv6 ones sometimes in brackets, and occasionally as host names
+    fn socket_addr(&self) -> Option<SocketAddr>{
+        let host = self.ip_addr.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>(){
+            return Some(SocketAddr::new(ip, self.port));
+        }
+
+        match (host, self.port).to_socket_addrs(){
+            Ok(mut e) => e.next(),
+            _ => None
+        }
+    }
+
     fn initiate_conn(&mut self, channel:&mut TorrentChannel<TorrentEvent>) -> Result<(), TorrentError>{
         if self.can_request || self.tcp_stream.is_some(){
             return Err(TorrentError::new(format!("Peer {} connection is already created", self.ip_addr.blue())));
         }
 
-        let tcp_stream = match TcpStream::connect_timeout(&SocketAddr::new(self.ip_addr.parse().unwrap(), self.port), Duration::from_secs_f32(CONNECTION_TIMEOUT)){
+        let address = match self.socket_addr(){
+            Some(e) => e,
+            _ => return Err(TorrentError::new(format!("Unable to resolve peer {}", self.ip_addr)))
+        };
+
+        let tcp_stream = match TcpStream::connect_timeout(&address, Duration::from_secs_f32(CONNECTION_TIMEOUT)){
             Ok(e) => e,
             _ => return Err(TorrentError::new(format!("Could not intiate connection with {}:{}", self.ip_addr, self.port)))
         };
@@ -225,7 +260,8 @@ impl Peer{
 
 
         self.tcp_stream = Some(tcp_stream);
-        let mut handshake_msg: Vec<u8> = format!("\x13{}\x00\x00\x00\x00\x00\x00\x00\x00", HANDSHAKE_MSG).into_bytes();
+        let mut handshake_msg: Vec<u8> = format!("\x13{}", HANDSHAKE_MSG).into_bytes();
+        handshake_msg.extend_from_slice(&HANDSHAKE_RESERVED);
         handshake_msg.extend_from_slice(&mut self.info_hash);
         handshake_msg.extend_from_slice(self.peer_id.as_bytes());
         if self.write_msg(handshake_msg).is_err(){
@@ -237,11 +273,19 @@ impl Peer{
             Err(e) => return Err(e)
         };
         let handshake_str = self.read_n(handshake_len as u64).unwrap();
-        let _reserved = self.read_n(8).unwrap();
+        let reserved = self.read_n(8).unwrap();
+        self.supports_extensions = reserved[5] & 0x10 != 0;
         let info_hash = self.read_n(20).unwrap();
-        let _peer_id = self.read_n(20).unwrap();
+        let peer_id = self.read_n(20).unwrap();
 
-        if info_hash != self.info_hash || String::from_utf8(handshake_str).unwrap() != String::from(HANDSHAKE_MSG){
+        // a tracker that told us the peer id to expect at this address may have handed out a stale or spoofed address
+        if let Some(expected) = &self.expected_peer_id{
+            if expected[..] != peer_id[..]{
+                return Err(self.close(channel, format!("Peer {} sent a different peer id than its tracker announced", self.ip_addr)));
+            }
+        }
+
+        if !self.accepted_info_hashes.iter().any(|e| e[..] == info_hash[..]) || String::from_utf8(handshake_str).unwrap() != String::from(HANDSHAKE_MSG){
             self.can_request = false;
         }
         else{
@@ -291,6 +335,7 @@ impl Peer{
             RequestType::Piece => self.receive_piece(payload),
             RequestType::Request => {thread_println!("Ignoring request"); Ok(RequestType::Request)},
             RequestType::Have => self.handle_update_bitfield(payload),
+            RequestType::Extended => Ok(RequestType::Extended),
             RequestType::Unknown => return Err(self.close(channel, format!("{} sent unknown request type", self.peer_id)))
         }
 
@@ -331,6 +376,85 @@ impl Peer{
         self.write_msg(msg)
     }
 
+    fn read_extended(&mut self) -> Result<(u8, Vec<u8>), TorrentError>{
+        loop{
+            match self.read_msg(){
+                Ok((RequestType::Extended, payload)) => {
+                    if payload.is_empty(){
+                        return Err(TorrentError::new(format!("Received empty extended message from {}", self.ip_addr)));
+                    }
+                    return Ok((payload[0], Vec::from(&payload[1..])));
+                },
+                // other messages (bitfield, have, ...) are not needed to fetch metadata
+                Ok(_) => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    // download the info dictionary from this peer using ut_metadata (BEP 9)
+    pub fn fetch_metadata(&mut self, channel: &mut TorrentChannel<TorrentEvent>) -> Result<Vec<u8>, TorrentError>{
+        match self.initiate_conn(channel){
+            Ok(_) => (),
+            Err(e) => return Err(e)
+        };
+
+        if !self.can_request{
+            return Err(TorrentError::new(format!("Peer {} sent an invalid handshake", self.ip_addr)));
+        }
+
+        if !self.supports_extensions{
+            return Err(TorrentError::new(format!("Peer {} does not support the extension protocol", self.ip_addr)));
+        }
+
+        if self.write_msg(Peer::make_msg(RequestType::Extended, metadata::handshake_payload())).is_err(){
+            return Err(TorrentError::new(format!("Cannot send extension handshake to {}", self.ip_addr)));
+        }
+
+        let (remote_id, metadata_size) = loop{
+            match self.read_extended(){
+                Ok((metadata::EXTENDED_HANDSHAKE_ID, payload)) => match metadata::parse_handshake(&payload){
+                    Ok(e) => break e,
+                    Err(e) => return Err(e)
+                },
+                Ok(_) => continue,
+                Err(e) => return Err(e)
+            }
+        };
+
+        let mut data: Vec<u8> = Vec::with_capacity(metadata_size);
+        let num_pieces = (metadata_size + metadata::METADATA_PIECE_SIZE - 1) / metadata::METADATA_PIECE_SIZE;
+
+        for piece in 0..num_pieces{
+            if self.write_msg(Peer::make_msg(RequestType::Extended, metadata::request_payload(remote_id, piece))).is_err(){
+                return Err(TorrentError::new(format!("Cannot request metadata from {}", self.ip_addr)));
+            }
+
+            let block = loop{
+                let payload = match self.read_extended(){
+                    Ok((metadata::UT_METADATA_ID, payload)) => payload,
+                    Ok(_) => continue,
+                    Err(e) => return Err(e)
+                };
+
+                match metadata::parse_data(&payload){
+                    Ok((index, block)) if index == piece => break block,
+                    Ok(_) => continue,
+                    Err(e) => return Err(e)
+                }
+            };
+
+            let expected = metadata::METADATA_PIECE_SIZE.min(metadata_size - data.len());
+            if block.len() != expected{
+                return Err(TorrentError::new(format!("Received metadata piece of the wrong size from {}", self.ip_addr)));
+            }
+
+            data.extend(block);
+        }
+
+        Ok(data)
+    }
+
     pub fn start_download(mut peer: Peer, mut channel: TorrentChannel<TorrentEvent>, output: Arc<Mutex<Vec<u8>>>) -> JoinHandle<()>{
         thread::spawn(move ||{
             let init = peer.initiate_conn(&mut channel);
@@ -339,8 +463,11 @@ impl Peer{
                 Err(e) => {thread_println!("{}", e.details); return;}
            };
 
-            // receive the bitfield from our peer
-            let bitfield_rsp = peer.recv_and_handle_msg(&mut channel);
+            // receive the bitfield from our peer, peers supporting extensions send their extension handshake first
+            let mut bitfield_rsp = peer.recv_and_handle_msg(&mut channel);
+            while let Ok(RequestType::Extended) = bitfield_rsp{
+                bitfield_rsp = peer.recv_and_handle_msg(&mut channel);
+            }
             if bitfield_rsp.is_err(){
                 thread_println!("Unable to read bitfield");
                 return;
@@ -474,7 +601,16 @@ impl Peer{
 
 #[cfg(test)]
 mod message_tests {
-    use crate::utils::bytes_to_u32;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crossbeam_channel::unbounded;
+    use crossbeam_queue::ArrayQueue;
+
+    use crate::peers::Peer;
+    use crate::utils::{bytes_to_u32, TorrentChannel};
 
     #[test]
     fn test_piece_parse(){
@@ -486,4 +622,36 @@ mod message_tests {
         assert_eq!(offset, 258);
     }
 
-}
\ No newline at end of file
+    // a peer that answers every handshake as the given peer id without extension support
+    fn handshaking_peer(peer_id: [u8; 20]) -> u16{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move ||{
+            for stream in listener.incoming(){
+                let mut stream = stream.unwrap();
+                let mut handshake = [0u8; 68];
+                stream.read_exact(&mut handshake).unwrap();
+                handshake[20..28].copy_from_slice(&[0; 8]);
+                handshake[48..68].copy_from_slice(&peer_id);
+                stream.write_all(&handshake).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_tracker_peer_id(){
+        let port = handshaking_peer([b'B'; 20]);
+        let (sender, receiver) = unbounded();
+        let mut channel = TorrentChannel::new(Arc::new(ArrayQueue::new(1)), sender, receiver);
+
+        let mut peer = Peer::new("127.0.0.1".to_string(), port, Some(vec![b'A'; 20]), "-NE001-aaaaaaaaaaaaa".to_string(), [1; 20], 0, 0);
+        let error = peer.fetch_metadata(&mut channel).unwrap_err();
+        assert!(error.details.contains("different peer id"));
+
+        // the handshake goes through when the peer is who the tracker said it is
+        let mut peer = Peer::new("127.0.0.1".to_string(), port, Some(vec![b'B'; 20]), "-NE001-aaaaaaaaaaaaa".to_string(), [1; 20], 0, 0);
+        let error = peer.fetch_metadata(&mut channel).unwrap_err();
+        assert!(error.details.contains("does not support the extension protocol"));
+    }
+}
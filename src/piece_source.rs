@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::io;
+use std::io::Write;
+
+use colored::Colorize;
+use crossbeam_channel::RecvTimeoutError;
+
+use crate::utils::{TorrentChannel, TorrentError, TorrentEvent, TorrentEventType};
+
+macro_rules! thread_println {
+    ($( $args:expr ),*) => {
+        writeln!(&mut io::stdout().lock(), $( $args ),* ).expect("Cannot write to stdout");
+    }
+}
+
+// give up on a source after this many failed requests in a row
+const MAX_FAILURES: u32 = 5;
+// how long to leave a source alone after a failure, doubled for every failure in a row so a short outage
+// doesn't use up every attempt at once
+#[cfg(not(test))]
+const FAILURE_BACKOFF: Duration = Duration::from_secs(2);
+#[cfg(test)]
+const FAILURE_BACKOFF: Duration = Duration::from_millis(20);
+// how long to wait for more work when the queue is empty
+const IDLE_WAIT: f32 = 0.5;
+
+pub enum FetchError{
+    // the server asked us to come back at this time, which doesn't count as a failure
+    Busy(TorrentError, Instant),
+    Failed(TorrentError)
+}
+
+// waits on the channel until `until`, false when the torrent said it is done (or went away)
+fn wait(channel: &TorrentChannel<TorrentEvent>, until: Instant) -> bool{
+    loop{
+        let now = Instant::now();
+        if until <= now{
+            return true;
+        }
+
+        match channel.receiver.recv_timeout(until - now){
+            Ok(e) if e.msg_type == TorrentEventType::Exit => return false,
+            Err(RecvTimeoutError::Disconnected) => return false,
+            Err(RecvTimeoutError::Timeout) => return true,
+            _ => continue
+        };
+    }
+}
+
+// works through the same queue as the peers with pieces from a server (web seeds and http seeds),
+// runs until the torrent sends Exit or the source fails too often
+pub fn start_download<F>(name: String, piece_byte_size: u64, mut fetch_piece: F, mut channel: TorrentChannel<TorrentEvent>, output: Arc<Mutex<Vec<u8>>>) -> JoinHandle<()>
+    where F: FnMut(usize, u64) -> Result<Vec<u8>, FetchError> + Send + 'static{
+    thread::spawn(move ||{
+        let mut failures = 0;
+        let mut is_active = false;
+
+        loop{
+            let (index, size) = match channel.work_queue.pop(){
+                Ok(e) => e,
+                // nothing to do until a piece fails somewhere else, or the torrent tells us it is done
+                _ => {
+                    if !wait(&channel, Instant::now() + Duration::from_secs_f32(IDLE_WAIT)){
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            let data = match fetch_piece(index as usize, size){
+                Ok(e) => e,
+                Err(FetchError::Busy(e, retry_at)) => {
+                    thread_println!("{}", e.details);
+                    channel.work_queue.push((index, size)).expect("Cannot add work to queue, this torrent will be incomplete");
+
+                    // leave the work to everyone else while the server has asked us to back off
+                    if !wait(&channel, retry_at){
+                        return;
+                    }
+                    continue;
+                },
+                Err(FetchError::Failed(e)) => {
+                    thread_println!("{}", e.details);
+                    channel.work_queue.push((index, size)).expect("Cannot add work to queue, this torrent will be incomplete");
+
+                    failures += 1;
+                    if failures >= MAX_FAILURES{
+                        thread_println!("[{}] Giving up on {}", "X".red(), name);
+                        if is_active{
+                            channel.send(TorrentEvent::new(TorrentEventType::Close)).unwrap_or(());
+                        }
+                        return;
+                    }
+
+                    if !wait(&channel, Instant::now() + FAILURE_BACKOFF * 2u32.pow(failures - 1)){
+                        return;
+                    }
+                    continue;
+                }
+            };
+            failures = 0;
+
+            let start = index as usize * piece_byte_size as usize;
+            output.lock().unwrap()[start .. start + data.len()].copy_from_slice(&data);
+
+            channel.send(TorrentEvent::with_index(TorrentEventType::Downloaded, index)).expect("Cannot tell manager we are complete, this torrent will be incomplete");
+            if !is_active{
+                if channel.send(TorrentEvent::new(TorrentEventType::Active)).is_err(){
+                    return;
+                }
+                is_active = true;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod piece_source_tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::{bounded, unbounded};
+    use crossbeam_queue::ArrayQueue;
+    use crate::piece_source::{start_download, FetchError, FAILURE_BACKOFF, MAX_FAILURES};
+    use crate::utils::{TorrentChannel, TorrentError, TorrentEvent, TorrentEventType};
+
+    #[test]
+    fn test_busy_then_exit(){
+        let queue = Arc::new(ArrayQueue::new(2));
+        queue.push((0, 4)).unwrap();
+        let (sender, events) = unbounded();
+        let (exit, receiver) = bounded(3);
+        let channel = TorrentChannel::new(queue.clone(), sender, receiver);
+
+        let mut busy = true;
+        let fetch = move |_, _| {
+            if busy{
+                busy = false;
+                return Err(FetchError::Busy(TorrentError::new(String::from("busy")), Instant::now() + Duration::from_millis(50)));
+            }
+            Ok(vec![1, 2, 3, 4])
+        };
+        let output = Arc::new(Mutex::new(vec![0u8; 4]));
+        let handle = start_download(String::from("test source"), 4, fetch, channel, output.clone());
+
+        // being busy isn't a failure, the piece is fetched once the wait is over
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(event.msg_type == TorrentEventType::Downloaded);
+        assert_eq!(*output.lock().unwrap(), vec![1, 2, 3, 4]);
+
+        exit.send(TorrentEvent::new(TorrentEventType::Exit)).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_failure_backoff(){
+        let queue = Arc::new(ArrayQueue::new(2));
+        queue.push((0, 4)).unwrap();
+        let (sender, events) = unbounded();
+        let (exit, receiver) = bounded(3);
+        let channel = TorrentChannel::new(queue.clone(), sender, receiver);
+
+        let mut failures = 0;
+        let fetch = move |_, _| {
+            if failures < MAX_FAILURES - 1{
+                failures += 1;
+                return Err(FetchError::Failed(TorrentError::new(String::from("unavailable"))));
+            }
+            Ok(vec![1, 2, 3, 4])
+        };
+        let start = Instant::now();
+        let handle = start_download(String::from("test source"), 4, fetch, channel, Arc::new(Mutex::new(vec![0u8; 4])));
+
+        // the source waits longer after every failure and is still around when the server is back
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(event.msg_type == TorrentEventType::Downloaded);
+        assert!(start.elapsed() >= FAILURE_BACKOFF * (1 + 2 + 4 + 8));
+
+        exit.send(TorrentEvent::new(TorrentEventType::Exit)).unwrap();
+        handle.join().unwrap();
+    }
+}
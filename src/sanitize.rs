@@ -0,0 +1,168 @@
+use crate::torrent_file::TorrentFile;
+
+// most filesystems limit a single name to 255 bytes
+const MAX_COMPONENT_BYTES: usize = 255;
+
+// names windows won't create files with, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+// a single path element that is safe to create inside the download directory, it can never
+// be empty, "." or ".." and never contains a separator
+pub fn sanitize_component(name: &str) -> String{
+    let mut ret: String = name.chars().map(|c| match c{
+        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+        c if c.is_control() => '_',
+        c => c
+    }).collect();
+
+    // windows silently drops trailing dots and spaces which can make two names the same
+    ret = ret.trim_end_matches(|c| c == '.' || c == ' ').to_string();
+    if ret.is_empty(){
+        return String::from("_");
+    }
+
+    let stem = ret.split('.').next().unwrap_or("").to_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()){
+        ret = format!("_{}", ret);
+    }
+
+    if ret.len() > MAX_COMPONENT_BYTES{
+        let mut end = MAX_COMPONENT_BYTES;
+        while !ret.is_char_boundary(end){
+            end -= 1;
+        }
+        ret.truncate(end);
+    }
+
+    ret
+}
+
+// a symlink target is only followed if it stays inside the torrent
+pub fn is_safe_relative_path(path: &str) -> bool{
+    !path.is_empty() && path.split('/').all(|e| sanitize_component(e) == e)
+}
+
+fn with_suffix(path: &str, count: usize) -> String{
+    let (dir, name) = match path.rfind('/'){
+        Some(e) => (&path[..e + 1], &path[e + 1..]),
+        _ => ("", path)
+    };
+
+    match name.rfind('.'){
+        Some(e) if e > 0 => format!("{}{}_{}{}", dir, &name[..e], count, &name[e..]),
+        _ => format!("{}{}_{}", dir, name, count)
+    }
+}
+
+fn sanitize_path(path: &str) -> String{
+    path.split('/').map(sanitize_component).collect::<Vec<String>>().join("/")
+}
+
+// set the path every file is written to, renaming anything unsafe and any files that end up with the same name
+// as another file or as a directory. directories are never renamed, so symlinks to them only need sanitizing
+pub fn sanitize_files(files: &mut Vec<TorrentFile>){
+    let paths: Vec<String> = files.iter().map(|e| sanitize_path(&e.path)).collect();
+
+    // compare without case since plenty of filesystems ignore it
+    let mut used: Vec<String> = Vec::new();
+    for path in paths.iter(){
+        let mut end = 0;
+        while let Some(e) = path[end..].find('/'){
+            end += e;
+            used.push(path[..end].to_lowercase());
+            end += 1;
+        }
+    }
+
+    for (file, original) in files.iter_mut().zip(paths){
+        let mut count = 1;
+        let mut disk_path = original.clone();
+        while used.contains(&disk_path.to_lowercase()){
+            disk_path = with_suffix(&original, count);
+            count += 1;
+        }
+
+        used.push(disk_path.to_lowercase());
+        file.disk_path = disk_path;
+    }
+
+    // symlink targets are relative to the top of the torrent and follow the file they point at
+    let renamed: Vec<(String, String)> = files.iter().map(|e| (e.path.clone(), e.disk_path.clone())).collect();
+    for file in files.iter_mut(){
+        file.disk_symlink_path = match &file.symlink_path{
+            Some(target) if !target.split('/').any(|e| e.is_empty() || e == "." || e == "..") => {
+                let top = match file.path.find('/'){
+                    Some(e) => &file.path[..e + 1],
+                    _ => ""
+                };
+                let full = format!("{}{}", top, target);
+                let disk = match renamed.iter().find(|(path, _)| *path == full){
+                    Some((_, disk)) => disk.clone(),
+                    _ => sanitize_path(&full)
+                };
+
+                match disk.find('/'){
+                    Some(e) if !top.is_empty() => Some(disk[e + 1..].to_string()),
+                    _ => Some(disk)
+                }
+            },
+            _ => None
+        };
+    }
+}
+
+#[cfg(test)]
+mod sanitize_tests {
+    use crate::sanitize::{sanitize_component, sanitize_files};
+    use crate::torrent_file::TorrentFile;
+
+    #[test]
+    fn test_unsafe_paths(){
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component(""), "_");
+        assert_eq!(sanitize_component("/etc"), "_etc");
+        assert_eq!(sanitize_component("a\0b"), "a_b");
+        assert_eq!(sanitize_component("con.txt"), "_con.txt");
+        assert_eq!(sanitize_component("C:"), "C_");
+
+        let mut files = vec![
+            TorrentFile::new(String::from("dir/../../evil"), 1),
+            TorrentFile::new(String::from("dir/A.txt"), 1),
+            TorrentFile::new(String::from("dir/a.txt"), 1),
+            TorrentFile::new(String::from("dir/a.txt."), 1)
+        ];
+        sanitize_files(&mut files);
+
+        let paths: Vec<&str> = files.iter().map(|e| e.disk_path.as_str()).collect();
+        assert_eq!(paths, vec!["dir/_/_/evil", "dir/A.txt", "dir/a_1.txt", "dir/a_2.txt"]);
+    }
+
+    #[test]
+    fn test_files_and_symlinks(){
+        let link = |path: &str, target: &str|{
+            let mut file = TorrentFile::new(String::from(path), 0);
+            file.symlink_path = Some(String::from(target));
+            file
+        };
+        let mut files = vec![
+            TorrentFile::new(String::from("t/a"), 1),
+            TorrentFile::new(String::from("t/a/b"), 1),
+            TorrentFile::new(String::from("t/con.txt"), 1),
+            link("t/l1", "a"),
+            link("t/l2", "con.txt"),
+            link("t/sub/l3", "a/b"),
+            link("t/l4", "../x")
+        ];
+        sanitize_files(&mut files);
+
+        // the file is renamed, never the directory, and the links follow the renamed files
+        let paths: Vec<&str> = files.iter().map(|e| e.disk_path.as_str()).collect();
+        assert_eq!(paths[..3], ["t/a_1", "t/a/b", "t/_con.txt"]);
+        let targets: Vec<Option<&str>> = files[3..].iter().map(|e| e.disk_symlink_path.as_deref()).collect();
+        assert_eq!(targets, vec![Some("a_1"), Some("_con.txt"), Some("a/b"), None]);
+    }
+}
@@ -0,0 +1,306 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bit_vec::BitVec;
+
+use crate::torrent_file::{TorrentInfo, TorrentFile};
+use crate::sanitize::is_safe_relative_path;
+use crate::utils::TorrentError;
+
+// writes each file of a torrent out of the downloaded data as soon as all of its pieces are verified
+#[derive(Debug, Clone)]
+pub struct Storage{
+    root: PathBuf,
+    single_file: bool,
+    files: Vec<(TorrentFile, u64)>,
+    piece_byte_size: u64,
+    written: Vec<bool>,
+    // files that don't match their sha1
+    failed: Vec<bool>
+}
+
+impl Storage{
+
+    // a single file torrent is written to `output`, otherwise `output` takes the place of the torrent's top directory
+    pub fn new(info: &TorrentInfo, output: &str) -> Storage{
+        let single_file = info.is_single_file();
+        let v2_only = info.hashes.is_empty() && info.v2.is_some();
+
+        let mut files: Vec<(TorrentFile, u64)> = Vec::new();
+        let mut offset = 0u64;
+        for file in info.files.iter(){
+            // v2 pieces never span files, so every file starts on a piece boundary of the downloaded data
+            if v2_only && offset % info.piece_byte_size != 0{
+                offset += info.piece_byte_size - offset % info.piece_byte_size;
+            }
+
+            let mut file = file.clone();
+            if !single_file{
+                file.disk_path = match file.disk_path.find('/'){
+                    Some(e) => file.disk_path[e + 1..].to_string(),
+                    _ => file.disk_path
+                };
+            }
+
+            files.push((file.clone(), offset));
+            offset += file.size_bytes as u64;
+        }
+
+        Storage{
+            root: PathBuf::from(output),
+            single_file,
+            written: files.iter().map(|(file, _)| file.is_padding).collect(),
+            failed: vec![false; files.len()],
+            files,
+            piece_byte_size: info.piece_byte_size
+        }
+    }
+
+    fn file_path(&self, file: &TorrentFile) -> PathBuf{
+        if self.single_file{
+            return self.root.clone();
+        }
+        self.root.join(&file.disk_path)
+    }
+
+    // the pieces holding a file's data, none for empty files and symlinks
+    fn pieces(&self, index: usize) -> std::ops::Range<usize>{
+        let (file, offset) = &self.files[index];
+        if file.size_bytes == 0 || file.symlink_path.is_some(){
+            return 0..0;
+        }
+
+        let first = offset / self.piece_byte_size;
+        let last = (offset + file.size_bytes as u64 - 1) / self.piece_byte_size;
+        first as usize..last as usize + 1
+    }
+
+    fn is_complete(&self, index: usize, have: &BitVec) -> bool{
+        self.pieces(index).all(|piece| have.get(piece).unwrap_or(false))
+    }
+
+    // only write the files a magnet link selected (BEP 53), returns the pieces that are needed for them.
+    // indices count every file in the torrent, padding files included
+    pub fn select(&mut self, selected: &[usize], num_pieces: usize) -> Vec<usize>{
+        let mut wanted = BitVec::from_elem(num_pieces, false);
+        for i in 0..self.files.len(){
+            if !selected.contains(&i){
+                self.written[i] = true;
+                continue;
+            }
+
+            if !self.files[i].0.is_padding{
+                for piece in self.pieces(i){
+                    wanted.set(piece, true);
+                }
+            }
+        }
+
+        (0..num_pieces).filter(|e| wanted[*e]).collect()
+    }
+
+    fn write_file(&self, file: &TorrentFile, offset: u64, data: &[u8]) -> Result<(), TorrentError>{
+        let path = self.file_path(file);
+        if let Some(parent) = path.parent(){
+            if !parent.as_os_str().is_empty(){
+                if let Err(e) = fs::create_dir_all(parent){
+                    return Err(TorrentError::new(format!("Unable to create directory {}: {}", parent.display(), e)));
+                }
+            }
+        }
+
+        if let Some(target) = &file.symlink_path{
+            return match &file.disk_symlink_path{
+                Some(e) if is_safe_relative_path(e) => Storage::write_symlink(&path, &file.disk_path, e),
+                _ => Err(TorrentError::new(format!("Refusing to create symlink {} pointing outside the torrent at {}", file.path, target)))
+            };
+        }
+
+        let content = &data[offset as usize .. offset as usize + file.size_bytes];
+        if let Err(e) = fs::write(&path, content){
+            return Err(TorrentError::new(format!("Unable to write {}: {}", path.display(), e)));
+        }
+
+        if file.is_executable{
+            return Storage::set_executable(&path);
+        }
+
+        // the h attribute is left alone: a hidden file's name already starts with a dot everywhere but windows, and
+        // the hidden attribute windows wants can't be set through std
+        Ok(())
+    }
+
+    // the whole file checked against the sha1 some torrents add to it, there is nothing to check without one
+    fn matches_sha1(file: &TorrentFile, offset: u64, data: &[u8]) -> bool{
+        let expected = match file.sha1{
+            Some(e) if file.symlink_path.is_none() => e,
+            _ => return true
+        };
+
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(&data[offset as usize .. offset as usize + file.size_bytes]);
+        hasher.digest().bytes() == expected
+    }
+
+    #[cfg(unix)]
+    fn set_executable(path: &Path) -> Result<(), TorrentError>{
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = match fs::metadata(path){
+            Ok(e) => e.permissions(),
+            Err(e) => return Err(TorrentError::new(e.to_string()))
+        };
+        permissions.set_mode(permissions.mode() | 0o111);
+
+        match fs::set_permissions(path, permissions){
+            Ok(_) => Ok(()),
+            Err(e) => Err(TorrentError::new(format!("Unable to make {} executable: {}", path.display(), e)))
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn set_executable(_path: &Path) -> Result<(), TorrentError>{
+        Ok(())
+    }
+
+    // symlink paths are relative to the top of the torrent, so climb back up from the link's directory
+    #[cfg(unix)]
+    fn write_symlink(path: &Path, relative_path: &str, target: &str) -> Result<(), TorrentError>{
+        let depth = relative_path.matches('/').count();
+        let target = format!("{}{}", "../".repeat(depth), target);
+
+        fs::remove_file(path).unwrap_or(());
+        match std::os::unix::fs::symlink(&target, path){
+            Ok(_) => Ok(()),
+            Err(e) => Err(TorrentError::new(format!("Unable to create symlink {}: {}", path.display(), e)))
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn write_symlink(path: &Path, _relative_path: &str, _target: &str) -> Result<(), TorrentError>{
+        Err(TorrentError::new(format!("Symlinks are not supported on this platform, skipping {}", path.display())))
+    }
+
+    // write every file that has all of its pieces, padding files are never written. a file that could not be
+    // written is tried again next time
+    pub fn write_completed(&mut self, have: &BitVec, data: &[u8]) -> Vec<TorrentError>{
+        let mut errors: Vec<TorrentError> = Vec::new();
+
+        for i in 0..self.files.len(){
+            if self.written[i] || self.failed[i] || !self.is_complete(i, have){
+                continue;
+            }
+
+            // its pieces passed their hashes so downloading them again gives the same bytes, the file is only reported
+            let (file, offset) = &self.files[i];
+            if !Storage::matches_sha1(file, *offset, data){
+                errors.push(TorrentError::new(format!("{} does not match its sha1, not writing it", file.path)));
+                self.failed[i] = true;
+                continue;
+            }
+
+            match self.write_file(file, *offset, data){
+                Ok(_) => self.written[i] = true,
+                Err(e) => errors.push(e)
+            };
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use bit_vec::BitVec;
+
+    use crate::torrent_builder::{TestDir, TorrentVersion};
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_skip_padding(){
+        let first: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+        let dir = TestDir::new("storage", &[("in/a.bin", first.clone()), ("in/sub/b.bin", second.clone())]);
+
+        let info = dir.torrent("in", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce")
+            .version(TorrentVersion::Hybrid));
+        assert!(info.files[1].is_padding);
+
+        // the data as it is downloaded, with the padding between the files
+        let mut data = first.clone();
+        data.resize(65536, 0);
+        data.extend(&second);
+
+        let output = dir.path.join("out");
+        let mut storage = Storage::new(&info, output.to_str().unwrap());
+        let mut have = BitVec::from_elem(info.num_pieces, false);
+        have.set(2, true);
+        assert!(storage.write_completed(&have, &data).is_empty());
+        assert_eq!(std::fs::read(output.join("sub").join("b.bin")).unwrap(), second);
+        assert!(!output.join("a.bin").exists());
+
+        have.set(0, true);
+        have.set(1, true);
+        assert!(storage.write_completed(&have, &data).is_empty());
+        assert_eq!(std::fs::read(output.join("a.bin")).unwrap(), first);
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_select_only(){
+        let first: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+        let dir = TestDir::new("storage-select", &[("in/a.bin", first.clone()), ("in/sub/b.bin", second.clone())]);
+
+        let info = dir.torrent("in", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce"));
+
+        // b.bin shares its piece with the end of a.bin, which is not written
+        let output = dir.path.join("out");
+        let mut storage = Storage::new(&info, output.to_str().unwrap());
+        assert_eq!(storage.select(&[1], info.num_pieces), vec![1]);
+
+        let mut data = first.clone();
+        data.extend(&second);
+        let mut have = BitVec::from_elem(info.num_pieces, false);
+        have.set(1, true);
+        assert!(storage.write_completed(&have, &data).is_empty());
+        assert_eq!(std::fs::read(output.join("sub").join("b.bin")).unwrap(), second);
+        assert!(!output.join("a.bin").exists());
+    }
+
+    #[test]
+    fn test_retry_failed_files(){
+        let first: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+        let dir = TestDir::new("storage-retry", &[("in/a.bin", first.clone()), ("in/sub/b.bin", second.clone())]);
+
+        let mut info = dir.torrent("in", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce"));
+        info.files[0].sha1 = Some([0; 20]);
+
+        let mut data = first.clone();
+        data.extend(&second);
+        let output = dir.path.join("out");
+        // a file in the way of b.bin's directory
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("sub"), b"").unwrap();
+
+        let mut storage = Storage::new(&info, output.to_str().unwrap());
+        let have = BitVec::from_elem(info.num_pieces, true);
+        let errors = storage.write_completed(&have, &data);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].details.contains("sha1"));
+        assert!(!output.join("a.bin").exists());
+
+        // b.bin is tried again once its directory can be created, a.bin was reported once and is left alone
+        std::fs::remove_file(output.join("sub")).unwrap();
+        assert!(storage.write_completed(&have, &data).is_empty());
+        assert!(!output.join("a.bin").exists());
+        assert_eq!(std::fs::read(output.join("sub").join("b.bin")).unwrap(), second);
+    }
+}
@@ -4,12 +4,19 @@ use rand::Rng;
 use rand::distributions::Alphanumeric;
 
 use crate::peers::Peer;
-use crate::tracker::Tracker;
+use crate::metadata;
+use crate::web_seed::WebSeed;
+use crate::http_seed::HttpSeed;
+use crate::storage::Storage;
+use crate::tracker::{Tracker, Announcer, AnnounceResponse, AnnounceEvent};
 use crate::torrent_file::{TorrentInfo};
-use crate::utils::{TorrentChannel, TorrentEventType, TorrentEvent};
+use crate::utils::{TorrentChannel, TorrentError, TorrentEventType, TorrentEvent, TransferStats};
 
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 use std::io;
 use std::io::{Write};
 
@@ -18,7 +25,7 @@ use colored::Colorize;
 use bit_vec::BitVec;
 
 use crossbeam_queue::ArrayQueue;
-use std::fs::File;
+use std::net::SocketAddr;
 use crossbeam_channel::{unbounded, Sender, Receiver, bounded};
 
 const ID_BEGIN: &str = "-NE001-";
@@ -33,14 +40,18 @@ macro_rules! thread_println {
 pub struct Torrent{
     pub id: String,
     pub port: u16,
-    pub downloaded: usize,
-    pub uploaded: usize,
+    pub key: u32,
+    pub stats: Arc<TransferStats>,
     pub info: TorrentInfo,
     work_queue: Arc<ArrayQueue<(u32, u64)>>,
     pub download_events: Option<Receiver<TorrentEvent>>,
     pub peer_thread_handles: Vec<JoinHandle<()>>,
     peer_channel_senders: Vec<Sender<TorrentEvent>>,
     pub torrent_mutex: Option<Arc<Mutex<Torrent>>>,
+    pub initial_peers: Vec<String>,
+    // the files to download when a magnet link selected some, everything when empty
+    pub select_only: Vec<usize>,
+    pub announce_to_all: bool,
     data: Vec<u8>,
     bitfield: BitVec
 }
@@ -55,15 +66,19 @@ impl Torrent{
         let mut ret = Torrent{
             id,
             port: 1881,
-            downloaded: 0,
-            uploaded: 0,
+            key: rand::thread_rng().gen(),
+            stats: Arc::new(TransferStats::new(info.byte_size)),
             info: info.clone(),
             download_events: None,
             peer_thread_handles: Vec::new(),
             peer_channel_senders: Vec::new(),
             torrent_mutex: None,
+            initial_peers: Vec::new(),
+            select_only: Vec::new(),
+            announce_to_all: false,
             bitfield: BitVec::new(),
-            work_queue: Arc::new(ArrayQueue::new(info.num_pieces)),
+            // torrents started from a magnet link do not know how many pieces they have yet
+            work_queue: Arc::new(ArrayQueue::new(info.num_pieces.max(1))),
             data: Vec::with_capacity(info.byte_size as usize)
         };
 
@@ -75,66 +90,217 @@ impl Torrent{
         mutex.clone()
     }
 
+    // info_hash is the hash the peer is known under, a tracker peer keeps the one it was announced with
+    fn make_peer(&self, ip_addr: String, port: u16, expected_peer_id: Option<Vec<u8>>, info_hash: [u8; 20]) -> Box<Peer>{
+        let mut peer = Box::new(Peer::new(
+            ip_addr,
+            port,
+            expected_peer_id,
+            self.id.clone(),
+            info_hash,
+            self.info.num_pieces,
+            self.info.piece_byte_size as usize
+        ));
+
+        for info_hash in self.info.info_hashes(){
+            peer.accept_info_hash(info_hash);
+        }
+        peer
+    }
+
+    // load the info dictionary fetched for a torrent started from a magnet link
+    fn resolve_metadata(&mut self, info_bytes: &[u8]) -> Result<(), TorrentError>{
+        if let Err(e) = self.info.load_metadata(info_bytes){
+            return Err(e);
+        }
+        thread_println!("[{}] Received metadata - {} pieces of {} bytes", "*".green(), self.info.num_pieces, self.info.piece_byte_size);
+
+        self.work_queue = Arc::new(ArrayQueue::new(self.info.num_pieces));
+        self.bitfield.reserve(self.info.num_pieces);
+        self.stats.left.store(self.info.byte_size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // peers that did not come from the torrent's trackers (a magnet link's x.pe, and DHT, PEX or LSD peers if
+    // they are ever added), a private torrent only ever talks to the peers its trackers hand out
+    fn untracked_peers(&self, addresses: &[String]) -> Vec<Box<Peer>>{
+        if self.info.private{
+            if !addresses.is_empty(){
+                thread_println!("[{}] Private torrent, ignoring {} peers that did not come from its trackers", "-".yellow(), addresses.len());
+            }
+            return Vec::new();
+        }
+
+        let mut peers: Vec<Box<Peer>> = Vec::new();
+        for address in addresses{
+            match address.parse::<SocketAddr>(){
+                Ok(e) => peers.push(self.make_peer(e.ip().to_string(), e.port(), None, self.info.info_hash)),
+                _ => thread_println!("[{}] Ignoring invalid peer address {}", "-".yellow(), address)
+            };
+        }
+        peers
+    }
+
+    // tracker warnings go into the event stream with everything else that happens to the download
+    fn send_warnings(response: &AnnounceResponse, sender: &Sender<TorrentEvent>){
+        for (url, warning) in response.warnings.iter(){
+            sender.send(TorrentEvent::tracker_warning(url, warning)).unwrap_or(());
+        }
+    }
+
+    fn spawn_peer(&mut self, peer: Box<Peer>, sender: &Sender<TorrentEvent>, output: &Arc<Mutex<Vec<u8>>>){
+        let (individual_sender, receiver): (Sender<TorrentEvent>, Receiver<TorrentEvent>) = bounded(3);
+        self.peer_channel_senders.push(individual_sender);
+        let channel: TorrentChannel<TorrentEvent> = TorrentChannel::new(self.work_queue.clone(), sender.clone(), receiver);
+        self.peer_thread_handles.push(Peer::start_download(*peer, channel, output.clone()));
+    }
+
     pub fn download(&mut self, output_name: &String){
         let (sender, receiver): (Sender<TorrentEvent>, Receiver<TorrentEvent>) = unbounded();
         self.download_events = Some(receiver);
-        let mut new_peers: Vec<Box<Peer>> = Tracker::announce(self).unwrap();
-        println!("[{}] Announced to tracker - received {} peers", "*".green(), new_peers.len());
+        // the trackers hear stopped when this goes out of scope, however the download ends
+        let mut announcer = Announcer::new(Tracker::new(self));
+        let tracker = announcer.tracker();
+        let announced = tracker.announce(AnnounceEvent::Started);
+        for (url, e) in tracker.errors.iter(){
+            thread_println!("{} ({})", e.details, url);
+        }
+
+        let mut new_peers: Vec<Box<Peer>> = match announced{
+            Ok(e) => {
+                Torrent::send_warnings(&e, &sender);
+                e.peers.into_iter().map(|peer| self.make_peer(peer.ip, peer.port, peer.peer_id, peer.info_hash)).collect()
+            },
+            // keep going with the peers and web seeds we have, the trackers are retried on the next announce
+            Err(e) => {thread_println!("{}, retrying in {} seconds", e.details, tracker.interval); Vec::new()}
+        };
+        println!("[{}] Announced to tracker - received {} peers, next announce in {} seconds", "*".green(), new_peers.len(), tracker.interval);
+
+        if !self.info.has_metadata(){
+            // the magnet's own peers are always asked as well, its trackers may be dead
+            let untracked = self.untracked_peers(&self.initial_peers);
+
+            // keep asking the trackers for new peers until one of them has the metadata
+            let info_bytes = loop{
+                let peers: Vec<&Peer> = new_peers.iter().chain(untracked.iter()).map(|e| &**e).collect();
+                thread_println!("[{}] Fetching metadata from {} peers", "*".green(), peers.len());
+                match metadata::fetch_from_peers(&peers, &self.info, &self.id){
+                    Ok(e) => break e,
+                    Err(e) => thread_println!("{}, asking the trackers for more peers in {} seconds", e.details, tracker.interval)
+                };
+                thread::sleep(Duration::from_secs(tracker.interval));
+
+                new_peers = match tracker.announce(AnnounceEvent::None){
+                    Ok(e) => e.peers.into_iter().map(|peer| self.make_peer(peer.ip, peer.port, peer.peer_id, peer.info_hash)).collect(),
+                    Err(e) => {thread_println!("{}", e.details); Vec::new()}
+                };
+            };
 
-        for i in 0..self.info.num_pieces{
-            let size;
-            if (i+1) as u64 * self.info.piece_byte_size > self.info.byte_size{
-                size = self.info.byte_size - (i as u64 * self.info.piece_byte_size);
+            // the metadata matches the info hash, so fetching it again would only give the same invalid torrent
+            if let Err(e) = self.resolve_metadata(&info_bytes){
+                thread_println!("[{}] The torrent's metadata is invalid: {}", "X".red(), e.details);
+                return;
             }
-            else{
-                size = self.info.piece_byte_size;
+
+            // the peers were created before we knew the piece layout so recreate them
+            new_peers = new_peers.into_iter().map(|peer| self.make_peer(peer.ip_addr.clone(), peer.port, peer.expected_peer_id.clone(), peer.info_hash)).collect();
+
+            // v2 only torrents verify pieces against the piece layers, and asking peers for those (BEP 52 hash
+            // requests) isn't supported yet
+            if self.info.hashes.is_empty() && !self.info.v2.as_ref().map_or(false, |e| e.has_piece_layers()){
+                thread_println!("[{}] This v2 torrent needs its piece layers which can't be fetched from peers yet, use the .torrent file instead", "X".red());
+                return;
+            }
+        }
+        new_peers.extend(self.untracked_peers(&self.initial_peers));
+
+        self.bitfield = BitVec::from_elem(self.info.num_pieces, false);
+        let mut storage = Storage::new(&self.info, output_name);
+
+        let wanted: Vec<usize> = if self.select_only.is_empty(){
+            (0..self.info.num_pieces).collect()
+        } else {
+            for index in self.select_only.iter().filter(|e| **e >= self.info.files.len()){
+                thread_println!("[{}] Ignoring selected file {}, the torrent only has {} files", "-".yellow(), index, self.info.files.len());
             }
-            self.work_queue.push((i as u32, size)).expect("Cannot add work to queue");
+            storage.select(&self.select_only, self.info.num_pieces)
+        };
+
+        let mut wanted_bytes = 0;
+        for i in wanted.iter(){
+            self.work_queue.push((*i as u32, self.info.piece_size(*i))).expect("Cannot add work to queue");
+            wanted_bytes += self.info.piece_size(*i);
         }
+        self.stats.left.store(wanted_bytes, Ordering::Relaxed);
 
-        let output_data: Vec<u8> = vec![0; self.info.byte_size as usize];
+        // every piece gets its full size in the buffer since v2 pieces end early at the end of each file
+        let output_data: Vec<u8> = vec![0; self.info.num_pieces * self.info.piece_byte_size as usize];
         let output_arc = Arc::new(Mutex::new(output_data));
 
+        let mut known_peers: Vec<(String, u16)> = new_peers.iter().map(|peer| (peer.ip_addr.clone(), peer.port)).collect();
         for _ in 0..new_peers.len() {
+            let peer = new_peers.pop().unwrap();
+            self.spawn_peer(peer, &sender, &output_arc);
+        }
+
+        // keep announcing in the background, a magnet link only knows all of its info hashes once it has the metadata
+        tracker.info_hashes = self.info.info_hashes();
+        let (response_sender, response_receiver): (Sender<AnnounceResponse>, Receiver<AnnounceResponse>) = unbounded();
+        announcer.start(response_sender);
+
+        for url in self.info.url_list.clone(){
+            let (individual_sender, receiver): (Sender<TorrentEvent>, Receiver<TorrentEvent>) = bounded(3);
+            self.peer_channel_senders.push(individual_sender);
+            let channel: TorrentChannel<TorrentEvent> = TorrentChannel::new(self.work_queue.clone(), sender.clone(), receiver);
+            self.peer_thread_handles.push(WebSeed::start_download(WebSeed::new(&url, &self.info), channel, output_arc.clone()));
+        }
+
+        for url in self.info.http_seeds.clone(){
             let (individual_sender, receiver): (Sender<TorrentEvent>, Receiver<TorrentEvent>) = bounded(3);
             self.peer_channel_senders.push(individual_sender);
-            let peer = new_peers.pop().unwrap();
             let channel: TorrentChannel<TorrentEvent> = TorrentChannel::new(self.work_queue.clone(), sender.clone(), receiver);
-            self.peer_thread_handles.push(Peer::start_download(*peer, channel, output_arc.clone()));
+            self.peer_thread_handles.push(HttpSeed::start_download(HttpSeed::new(&url, &self.info), channel, output_arc.clone()));
         }
 
-        let downloaded = self.download_events.as_ref().unwrap();
+        let downloaded = self.download_events.clone().unwrap();
         let mut pieces_received = 0;
         let mut num_peers = 0;
 
-        let mut hasher = sha1::Sha1::new();
-        while pieces_received != self.info.num_pieces{
+        while pieces_received != wanted.len(){
+            if let Ok(response) = response_receiver.try_recv(){
+                Torrent::send_warnings(&response, &sender);
+                for peer in response.peers{
+                    if known_peers.contains(&(peer.ip.clone(), peer.port)){
+                        continue;
+                    }
+                    known_peers.push((peer.ip.clone(), peer.port));
+                    let peer = self.make_peer(peer.ip, peer.port, peer.peer_id, peer.info_hash);
+                    self.spawn_peer(peer, &sender, &output_arc);
+                }
+            }
+
             let work_done = downloaded.try_recv();
             if work_done.is_ok(){
                 let event = work_done.unwrap();
                 if event.msg_type == TorrentEventType::Downloaded {
-                    hasher.reset();
                     let index = event.index_downloaded.unwrap();
+                    let size = self.info.piece_size(index as usize);
                     let start = index as u64 * self.info.piece_byte_size;
-                    let end = self.info.byte_size.min((index + 1) as u64 * self.info.piece_byte_size);
-                    hasher.update(&output_arc.lock().unwrap()[start as usize .. end as usize]);
-                    if hasher.digest().bytes() != self.info.hashes[index as usize]{
+                    let end = start + size;
+                    if !self.info.verify_piece(index as usize, &output_arc.lock().unwrap()[start as usize .. end as usize]){
                         thread_println!("[{}] Received invalid piece at index {}", "X".red(), index);
-                        let size;
-
-                        if index == (self.info.num_pieces - 1) as u32 {
-                            size = self.info.byte_size - (index * self.info.piece_byte_size as u32) as u64;
-                        }
-                        else{
-                            size = self.info.piece_byte_size;
-                        }
-
                         self.work_queue.push((index, size)).expect("Cannot add work to queue");
                         continue;
                     }
 
                     pieces_received += 1;
-                    thread_println!("[{}] ({:.2}%) Downloaded piece {} from {} peers", "*".green(), (pieces_received as f32 / self.info.num_pieces as f32) * 100.0, index, num_peers);
+                    self.bitfield.set(index as usize, true);
+                    let total = self.stats.downloaded.fetch_add(size, Ordering::Relaxed) + size;
+                    self.stats.left.store(wanted_bytes.saturating_sub(total), Ordering::Relaxed);
+                    for e in storage.write_completed(&self.bitfield, &output_arc.lock().unwrap()){
+                        thread_println!("{}", e.details);
+                    }
+                    thread_println!("[{}] ({:.2}%) Downloaded piece {} from {} peers", "*".green(), (pieces_received as f32 / wanted.len() as f32) * 100.0, index, num_peers);
                 }
                 else if event.msg_type == TorrentEventType::Active {
                     num_peers += 1;
@@ -142,12 +308,21 @@ impl Torrent{
                 else if event.msg_type == TorrentEventType::Close{
                     num_peers -= 1;
                 }
+                else if let Some((url, warning)) = event.tracker_warning{
+                    thread_println!("[{}] Tracker {} warns: {}", "-".yellow(), url, warning);
+                }
             }
         }
 
         thread_println!("Completed download - joining threads");
-        let file = File::create(output_name);
-        file.unwrap().write_all(&output_arc.lock().unwrap()).expect("Unable to write to file");
+
+        // web and http seeds wait on their channel once the queue is empty, tell them no more work is coming
+        for sender in self.peer_channel_senders.iter(){
+            sender.try_send(TorrentEvent::new(TorrentEventType::Exit)).unwrap_or(());
+        }
+
+        // we don't seed yet, so finishing the download also ends our part in the swarm
+        drop(announcer);
     }
 
     pub fn join(&mut self){
@@ -155,4 +330,36 @@ impl Torrent{
             self.peer_thread_handles.pop().unwrap().join().expect("Unable to join thread");
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod torrent_tests {
+    use crossbeam_channel::unbounded;
+    use crate::torrent::Torrent;
+    use crate::torrent_builder::TestDir;
+    use crate::tracker::AnnounceResponse;
+    use crate::utils::TorrentEventType;
+
+    #[test]
+    fn test_warning_events(){
+        let (sender, receiver) = unbounded();
+        let response = AnnounceResponse{warnings: vec![(String::from("http://tracker.example.com/announce"), String::from("slow down"))], ..Default::default()};
+        Torrent::send_warnings(&response, &sender);
+
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.msg_type, TorrentEventType::TrackerWarning);
+        assert_eq!(event.tracker_warning, Some((String::from("http://tracker.example.com/announce"), String::from("slow down"))));
+    }
+
+    #[test]
+    fn test_private_rejects_untracked_peers(){
+        let dir = TestDir::new("torrent-private", &[("a.bin", vec![3u8; 1000])]);
+        let addresses = vec![String::from("127.0.0.1:6881"), String::from("[::1]:6882")];
+
+        let public = Torrent::new(dir.torrent("a.bin", |e| e.announce("http://tracker.example.com/announce")));
+        assert_eq!(public.lock().unwrap().untracked_peers(&addresses).len(), 2);
+
+        let private = Torrent::new(dir.torrent("a.bin", |e| e.announce("http://tracker.example.com/announce").private(true)));
+        assert!(private.lock().unwrap().untracked_peers(&addresses).is_empty());
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bencode::Bencode;
+use bencode::util::ByteString;
+use crossbeam_channel::unbounded;
+
+use crate::torrent_v2::{HashV2, block_hashes, blocks_per_piece, merkle_root, pad_hash};
+use crate::utils::TorrentError;
+
+const MIN_PIECE_SIZE: u64 = 16384;
+const MAX_PIECE_SIZE: u64 = 16 * 1024 * 1024;
+// automatically picked piece sizes aim for roughly this many pieces
+const TARGET_NUM_PIECES: u64 = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentVersion{
+    V1,
+    V2,
+    Hybrid
+}
+
+// a file (or padding) in the order it appears in the torrent's data
+#[derive(Debug, Clone)]
+struct LayoutEntry{
+    path: Vec<String>,
+    source: Option<PathBuf>,
+    size_bytes: u64,
+    offset: u64
+}
+
+#[derive(Debug, Clone)]
+struct PieceHashes{
+    sha1: [u8; 20],
+    blocks: Vec<HashV2>
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder{
+    path: PathBuf,
+    piece_byte_size: Option<u64>,
+    announce_tiers: Vec<Vec<String>>,
+    comment: Option<String>,
+    creator: String,
+    creation_date: Option<u64>,
+    private: bool,
+    web_seeds: Vec<String>,
+    version: TorrentVersion,
+    num_threads: usize
+}
+
+fn byte_string(value: &str) -> Bencode{
+    Bencode::ByteString(value.as_bytes().to_vec())
+}
+
+fn path_list(path: &Vec<String>) -> Bencode{
+    Bencode::List(path.iter().map(|e| byte_string(e)).collect())
+}
+
+fn collect_files(root: &Path, relative: &mut Vec<String>, files: &mut Vec<(Vec<String>, PathBuf)>) -> Result<(), TorrentError>{
+    let mut entries: Vec<PathBuf> = match std::fs::read_dir(root){
+        Ok(e) => e.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(e) => return Err(TorrentError::new(format!("Unable to read directory {}: {}", root.display(), e)))
+    };
+    entries.sort();
+
+    for entry in entries{
+        let name = entry.file_name().unwrap().to_string_lossy().to_string();
+        relative.push(name);
+        if entry.is_dir(){
+            match collect_files(&entry, relative, files){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+        }
+        else if entry.is_file(){
+            files.push((relative.clone(), entry));
+        }
+        relative.pop();
+    }
+
+    Ok(())
+}
+
+// read `length` bytes of the torrent's data starting at `offset`, padding is read as zeros
+fn read_range(layout: &Vec<LayoutEntry>, offset: u64, length: u64) -> Result<Vec<u8>, TorrentError>{
+    let mut ret: Vec<u8> = vec![0; length as usize];
+    for entry in layout.iter(){
+        let end = entry.offset + entry.size_bytes;
+        if end <= offset || entry.offset >= offset + length{
+            continue;
+        }
+
+        let source = match &entry.source{
+            Some(e) => e,
+            _ => continue
+        };
+
+        let start = offset.max(entry.offset);
+        let stop = (offset + length).min(end);
+        let mut file = match File::open(source){
+            Ok(e) => e,
+            Err(e) => return Err(TorrentError::new(format!("Unable to open {}: {}", source.display(), e)))
+        };
+
+        let buf = &mut ret[(start - offset) as usize..(stop - offset) as usize];
+        if file.seek(SeekFrom::Start(start - entry.offset)).is_err() || file.read_exact(buf).is_err(){
+            return Err(TorrentError::new(format!("Unable to read {}", source.display())));
+        }
+    }
+
+    Ok(ret)
+}
+
+impl TorrentBuilder{
+    pub fn new<P: AsRef<Path>>(path: P) -> TorrentBuilder{
+        TorrentBuilder{
+            path: path.as_ref().to_path_buf(),
+            piece_byte_size: None,
+            announce_tiers: Vec::new(),
+            comment: None,
+            creator: format!("Neon/{}", env!("CARGO_PKG_VERSION")),
+            creation_date: None,
+            private: false,
+            web_seeds: Vec::new(),
+            version: TorrentVersion::V1,
+            num_threads: thread::available_parallelism().map(|e| e.get()).unwrap_or(4)
+        }
+    }
+
+    pub fn piece_size(mut self, piece_byte_size: u64) -> TorrentBuilder{
+        self.piece_byte_size = Some(piece_byte_size);
+        self
+    }
+
+    // each call adds a new tier, the first tracker of the first tier is used as the announce url
+    pub fn announce(mut self, url: &str) -> TorrentBuilder{
+        self.announce_tiers.push(vec![url.to_string()]);
+        self
+    }
+
+    pub fn announce_tier(mut self, urls: Vec<String>) -> TorrentBuilder{
+        if !urls.is_empty(){
+            self.announce_tiers.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> TorrentBuilder{
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by(mut self, creator: &str) -> TorrentBuilder{
+        self.creator = creator.to_string();
+        self
+    }
+
+    pub fn creation_date(mut self, timestamp: u64) -> TorrentBuilder{
+        self.creation_date = Some(timestamp);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> TorrentBuilder{
+        self.private = private;
+        self
+    }
+
+    pub fn web_seed(mut self, url: &str) -> TorrentBuilder{
+        self.web_seeds.push(url.to_string());
+        self
+    }
+
+    pub fn version(mut self, version: TorrentVersion) -> TorrentBuilder{
+        self.version = version;
+        self
+    }
+
+    pub fn threads(mut self, num_threads: usize) -> TorrentBuilder{
+        self.num_threads = num_threads.max(1);
+        self
+    }
+
+    fn name(&self) -> Result<String, TorrentError>{
+        match self.path.canonicalize(){
+            Ok(e) => match e.file_name(){
+                Some(name) => Ok(name.to_string_lossy().to_string()),
+                _ => Err(TorrentError::new(format!("Unable to find the name of {}", self.path.display())))
+            },
+            Err(e) => Err(TorrentError::new(format!("Unable to read {}: {}", self.path.display(), e)))
+        }
+    }
+
+    fn choose_piece_size(&self, total_size: u64) -> u64{
+        match self.piece_byte_size{
+            Some(e) => e,
+            _ => (total_size / TARGET_NUM_PIECES).next_power_of_two().max(MIN_PIECE_SIZE).min(MAX_PIECE_SIZE)
+        }
+    }
+
+    // v2 and hybrid torrents align every file to a piece boundary, hybrid torrents list the padding as files
+    fn build_layout(&self, files: Vec<(Vec<String>, PathBuf)>, piece_byte_size: u64) -> Result<Vec<LayoutEntry>, TorrentError>{
+        let mut layout: Vec<LayoutEntry> = Vec::new();
+        let mut offset = 0;
+        let num_files = files.len();
+
+        for (i, (path, source)) in files.into_iter().enumerate(){
+            let size_bytes = match std::fs::metadata(&source){
+                Ok(e) => e.len(),
+                Err(e) => return Err(TorrentError::new(format!("Unable to read {}: {}", source.display(), e)))
+            };
+
+            layout.push(LayoutEntry{ path, source: Some(source), size_bytes, offset });
+            offset += size_bytes;
+
+            let remainder = size_bytes % piece_byte_size;
+            if self.version != TorrentVersion::V1 && remainder != 0 && i != num_files - 1{
+                let pad_size = piece_byte_size - remainder;
+                layout.push(LayoutEntry{ path: vec![String::from(".pad"), pad_size.to_string()], source: None, size_bytes: pad_size, offset });
+                offset += pad_size;
+            }
+        }
+
+        Ok(layout)
+    }
+
+    fn hash_pieces(&self, layout: &Vec<LayoutEntry>, piece_byte_size: u64, total_size: u64) -> Result<Vec<PieceHashes>, TorrentError>{
+        let num_pieces = ((total_size + piece_byte_size - 1) / piece_byte_size) as usize;
+        let next_piece = Arc::new(AtomicUsize::new(0));
+        let layout = Arc::new(layout.clone());
+        let (sender, receiver) = unbounded();
+
+        for _ in 0..self.num_threads.min(num_pieces.max(1)){
+            let next_piece = next_piece.clone();
+            let layout = layout.clone();
+            let sender = sender.clone();
+            thread::spawn(move ||{
+                loop{
+                    let index = next_piece.fetch_add(1, Ordering::SeqCst);
+                    if index >= num_pieces{
+                        return;
+                    }
+
+                    let offset = index as u64 * piece_byte_size;
+                    let data = match read_range(&layout, offset, piece_byte_size.min(total_size - offset)){
+                        Ok(e) => e,
+                        Err(e) => {sender.send(Err(e)).unwrap_or(()); return}
+                    };
+
+                    let mut hasher = sha1::Sha1::new();
+                    hasher.update(&data);
+
+                    // v2 block hashes only cover the file's data, not any padding following it
+                    let file_end = layout.iter()
+                        .find(|e| e.source.is_some() && e.offset <= offset && offset < e.offset + e.size_bytes)
+                        .map(|e| e.offset + e.size_bytes)
+                        .unwrap_or(offset);
+                    let blocks = block_hashes(&data[..(file_end.min(offset + data.len() as u64) - offset) as usize]);
+
+                    sender.send(Ok((index, PieceHashes{ sha1: hasher.digest().bytes(), blocks }))).unwrap_or(());
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pieces: Vec<Option<PieceHashes>> = vec![None; num_pieces];
+        for result in receiver.iter(){
+            match result{
+                Ok((index, hashes)) => pieces[index] = Some(hashes),
+                Err(e) => return Err(e)
+            };
+        }
+
+        match pieces.into_iter().collect::<Option<Vec<PieceHashes>>>(){
+            Some(e) => Ok(e),
+            _ => Err(TorrentError::new(String::from("Unable to hash every piece")))
+        }
+    }
+
+    // returns the file tree and the piece layers for the v2 part of the torrent
+    fn v2_fields(&self, layout: &Vec<LayoutEntry>, pieces: &Vec<PieceHashes>, piece_byte_size: u64, single_file: bool) -> (Bencode, Bencode){
+        let blocks = blocks_per_piece(piece_byte_size);
+        let mut file_tree: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+        let mut piece_layers: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+
+        for entry in layout.iter().filter(|e| e.source.is_some()){
+            let mut file_entry: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+            file_entry.insert(ByteString::from_str("length"), Bencode::Number(entry.size_bytes as i64));
+
+            if entry.size_bytes > 0{
+                let first_piece = (entry.offset / piece_byte_size) as usize;
+                let num_pieces = ((entry.size_bytes + piece_byte_size - 1) / piece_byte_size) as usize;
+                let file_pieces = &pieces[first_piece..first_piece + num_pieces];
+
+                let root = if entry.size_bytes <= piece_byte_size{
+                    let leaves = file_pieces[0].blocks.clone();
+                    let width = leaves.len().next_power_of_two();
+                    merkle_root(leaves, width, [0; 32])
+                }
+                else{
+                    let layer: Vec<HashV2> = file_pieces.iter().map(|e| merkle_root(e.blocks.clone(), blocks, [0; 32])).collect();
+                    let root = merkle_root(layer.clone(), layer.len().next_power_of_two(), pad_hash(blocks.trailing_zeros()));
+                    piece_layers.insert(ByteString::from_slice(&root), Bencode::ByteString(layer.concat()));
+                    root
+                };
+                file_entry.insert(ByteString::from_str("pieces root"), Bencode::ByteString(root.to_vec()));
+            }
+
+            let mut node: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+            node.insert(ByteString::from_str(""), Bencode::Dict(file_entry));
+
+            // insert the file entry at the end of its path, creating directories along the way
+            let path: &[String] = if single_file { &entry.path[entry.path.len() - 1..] } else { &entry.path };
+            let mut directory = &mut file_tree;
+            for name in path[..path.len() - 1].iter(){
+                let child = directory.entry(ByteString::from_str(name)).or_insert(Bencode::Dict(BTreeMap::new()));
+                directory = match child{
+                    Bencode::Dict(e) => e,
+                    _ => unreachable!()
+                };
+            }
+            directory.insert(ByteString::from_str(&path[path.len() - 1]), Bencode::Dict(node));
+        }
+
+        (Bencode::Dict(file_tree), Bencode::Dict(piece_layers))
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, TorrentError>{
+        let name = match self.name(){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        let single_file = self.path.is_file();
+        let mut files: Vec<(Vec<String>, PathBuf)> = Vec::new();
+        if single_file{
+            files.push((vec![name.clone()], self.path.clone()));
+        }
+        else{
+            match collect_files(&self.path, &mut Vec::new(), &mut files){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+        }
+
+        if files.is_empty(){
+            return Err(TorrentError::new(format!("No files found in {}", self.path.display())));
+        }
+
+        let mut total_size = 0;
+        for (_, source) in files.iter(){
+            total_size += match std::fs::metadata(source){
+                Ok(e) => e.len(),
+                Err(e) => return Err(TorrentError::new(format!("Unable to read {}: {}", source.display(), e)))
+            };
+        }
+
+        let piece_byte_size = self.choose_piece_size(total_size);
+        if piece_byte_size < MIN_PIECE_SIZE || !piece_byte_size.is_power_of_two(){
+            return Err(TorrentError::new(format!("Piece size must be a power of two of at least {} bytes", MIN_PIECE_SIZE)));
+        }
+
+        let layout = match self.build_layout(files, piece_byte_size){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        let data_size = layout.last().map(|e| e.offset + e.size_bytes).unwrap_or(0);
+        let pieces = match self.hash_pieces(&layout, piece_byte_size, data_size){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        let mut info: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+        info.insert(ByteString::from_str("name"), byte_string(&name));
+        info.insert(ByteString::from_str("piece length"), Bencode::Number(piece_byte_size as i64));
+        if self.private{
+            info.insert(ByteString::from_str("private"), Bencode::Number(1));
+        }
+
+        if self.version != TorrentVersion::V2{
+            let hashes: Vec<u8> = pieces.iter().flat_map(|e| e.sha1.to_vec()).collect();
+            info.insert(ByteString::from_str("pieces"), Bencode::ByteString(hashes));
+
+            if single_file{
+                info.insert(ByteString::from_str("length"), Bencode::Number(layout[0].size_bytes as i64));
+            }
+            else{
+                let file_list: Vec<Bencode> = layout.iter().map(|entry| {
+                    let mut file: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+                    file.insert(ByteString::from_str("length"), Bencode::Number(entry.size_bytes as i64));
+                    file.insert(ByteString::from_str("path"), path_list(&entry.path));
+                    if entry.source.is_none(){
+                        file.insert(ByteString::from_str("attr"), byte_string("p"));
+                    }
+                    Bencode::Dict(file)
+                }).collect();
+                info.insert(ByteString::from_str("files"), Bencode::List(file_list));
+            }
+        }
+
+        let mut torrent: BTreeMap<ByteString, Bencode> = BTreeMap::new();
+        if self.version != TorrentVersion::V1{
+            let (file_tree, piece_layers) = self.v2_fields(&layout, &pieces, piece_byte_size, single_file);
+            info.insert(ByteString::from_str("meta version"), Bencode::Number(2));
+            info.insert(ByteString::from_str("file tree"), file_tree);
+            torrent.insert(ByteString::from_str("piece layers"), piece_layers);
+        }
+
+        torrent.insert(ByteString::from_str("info"), Bencode::Dict(info));
+
+        if let Some(url) = self.announce_tiers.first().and_then(|tier| tier.first()){
+            torrent.insert(ByteString::from_str("announce"), byte_string(url));
+        }
+
+        if self.announce_tiers.len() > 1 || self.announce_tiers.iter().any(|tier| tier.len() > 1){
+            let tiers: Vec<Bencode> = self.announce_tiers.iter().map(|tier| path_list(tier)).collect();
+            torrent.insert(ByteString::from_str("announce-list"), Bencode::List(tiers));
+        }
+
+        if let Some(comment) = &self.comment{
+            torrent.insert(ByteString::from_str("comment"), byte_string(comment));
+        }
+
+        torrent.insert(ByteString::from_str("created by"), byte_string(&self.creator));
+
+        let creation_date = match self.creation_date{
+            Some(e) => e,
+            _ => SystemTime::now().duration_since(UNIX_EPOCH).map(|e| e.as_secs()).unwrap_or(0)
+        };
+        torrent.insert(ByteString::from_str("creation date"), Bencode::Number(creation_date as i64));
+
+        if !self.web_seeds.is_empty(){
+            torrent.insert(ByteString::from_str("url-list"), path_list(&self.web_seeds));
+        }
+
+        match Bencode::Dict(torrent).to_bytes(){
+            Ok(e) => Ok(e),
+            Err(e) => Err(TorrentError::new(format!("Unable to encode torrent: {}", e)))
+        }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, output: P) -> Result<(), TorrentError>{
+        let data = match self.build(){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        match std::fs::write(output.as_ref(), data){
+            Ok(_) => Ok(()),
+            Err(e) => Err(TorrentError::new(format!("Unable to write {}: {}", output.as_ref().display(), e)))
+        }
+    }
+}
+
+// a scratch directory of files that torrents are built from in tests, removed again when dropped
+#[cfg(test)]
+pub struct TestDir{
+    pub path: PathBuf
+}
+
+#[cfg(test)]
+impl TestDir{
+    pub fn new(name: &str, files: &[(&str, Vec<u8>)]) -> TestDir{
+        let path = std::env::temp_dir().join(format!("neon-{}-{}", name, std::process::id()));
+        for (file, content) in files{
+            let file = path.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, content).unwrap();
+        }
+        TestDir{path}
+    }
+
+    // builds a torrent of `root` inside the directory, `configure` sets everything but the path
+    pub fn torrent<F: FnOnce(TorrentBuilder) -> TorrentBuilder>(&self, root: &str, configure: F) -> crate::torrent_file::TorrentInfo{
+        let data = configure(TorrentBuilder::new(self.path.join(root))).build().unwrap();
+        crate::torrent_file::TorrentInfo::from_buffer(data).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir{
+    fn drop(&mut self){
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use crate::torrent_builder::{TestDir, TorrentVersion};
+
+    #[test]
+    fn test_build_hybrid(){
+        let first: Vec<u8> = (0..70000).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = (0..20000).map(|i| (i % 13) as u8).collect();
+        let dir = TestDir::new("builder", &[("in/a.bin", first.clone()), ("in/sub/b.bin", second.clone())]);
+
+        let info = dir.torrent("in", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce")
+            .version(TorrentVersion::Hybrid)
+            .private(true));
+
+        assert!(info.is_hybrid());
+        assert!(info.private);
+        assert_eq!(info.num_pieces, 4);
+        assert!(info.v2.as_ref().unwrap().verify_piece(1, &first[32768..65536]));
+        assert!(info.v2.as_ref().unwrap().verify_piece(3, &second));
+    }
+}
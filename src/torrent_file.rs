@@ -7,37 +7,188 @@ use std::convert::TryInto;
 
 use bencode::{FromBencode, Bencode};
 use bencode::util::ByteString;
-use crate::utils::TorrentError;
+use crate::utils::{TorrentError, MetainfoError};
+use crate::magnet::MagnetLink;
+use crate::bencode_raw;
+use crate::sanitize::sanitize_files;
+use crate::announce_list::AnnounceList;
+use crate::torrent_v2::{TorrentInfoV2, HashV2, sha256, truncate_hash};
 
 type Hash = [u8; 20];
 
+// torrents with more pieces than this get very large, a bigger piece size should be used
+const HUGE_PIECE_COUNT: usize = 1 << 17;
+
+// invalid utf-8 in a name keeps its valid parts and has every other byte escaped as %XX, so a torrent
+// from a legacy client always maps to the same file names
+pub fn decode_name(bytes: &[u8]) -> String{
+    let mut ret = String::new();
+    let mut rest = bytes;
+
+    loop{
+        match std::str::from_utf8(rest){
+            Ok(e) => {ret += e; return ret},
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                ret += std::str::from_utf8(valid).unwrap();
+
+                let bad = e.error_len().unwrap_or(invalid.len());
+                for byte in &invalid[..bad]{
+                    ret += &format!("%{:02X}", byte);
+                }
+                rest = &invalid[bad..];
+            }
+        }
+    }
+}
+
+// the raw bytes of a name and its text, taken from the name.utf-8 style key when the torrent has one
+fn get_name(dict: &BTreeMap<ByteString, Bencode>, key: &str) -> Option<(Vec<u8>, String)>{
+    let raw: Vec<u8> = match dict.get(&ByteString::from_str(key)){
+        Some(Bencode::ByteString(e)) => e.clone(),
+        _ => return None
+    };
+
+    let text = match dict.get(&ByteString::from_str(&format!("{}.utf-8", key))){
+        Some(Bencode::ByteString(e)) => match String::from_utf8(e.clone()){
+            Ok(e) => e,
+            _ => decode_name(&raw)
+        },
+        _ => decode_name(&raw)
+    };
+
+    Some((raw, text))
+}
+
+fn get_path(dict: &BTreeMap<ByteString, Bencode>) -> Option<(Vec<Vec<u8>>, Vec<String>)>{
+    let raw: Vec<Vec<u8>> = match dict.get(&ByteString::from_str("path")){
+        Some(Bencode::List(e)) => {
+            let mut components: Vec<Vec<u8>> = Vec::new();
+            for component in e{
+                match component{
+                    Bencode::ByteString(e) => components.push(e.clone()),
+                    _ => return None
+                };
+            }
+            components
+        },
+        _ => return None
+    };
+
+    let text: Vec<String> = match dict.get(&ByteString::from_str("path.utf-8")){
+        Some(e) => match FromBencode::from_bencode(e){
+            Ok(e) => e,
+            _ => raw.iter().map(|e| decode_name(e)).collect()
+        },
+        _ => raw.iter().map(|e| decode_name(e)).collect()
+    };
+
+    Some((raw, text))
+}
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TorrentFile{
     pub path: String,
-    pub size_bytes: usize
+    // the path components exactly as they appear in the torrent, which may not be utf-8 and may even contain a '/'
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub raw_path: Vec<Vec<u8>>,
+    // where the file is written, the path with anything unsafe renamed
+    pub disk_path: String,
+    pub size_bytes: usize,
+    pub is_padding: bool,
+    pub is_executable: bool,
+    pub is_hidden: bool,
+    pub symlink_path: Option<String>,
+    // where the symlink points on disk, with the same renames as disk_path. none when the target leaves the torrent
+    pub disk_symlink_path: Option<String>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub sha1: Option<Hash>
+}
+
+impl TorrentFile{
+    pub fn new(path: String, size_bytes: usize) -> TorrentFile{
+        TorrentFile{
+            disk_path: path.clone(),
+            raw_path: path.split('/').map(|e| e.as_bytes().to_vec()).collect(),
+            path,
+            size_bytes,
+            is_padding: false,
+            is_executable: false,
+            is_hidden: false,
+            symlink_path: None,
+            disk_symlink_path: None,
+            sha1: None
+        }
+    }
+
+    // BEP 47 attributes, p = padding, x = executable, h = hidden and l = symlink
+    fn process_attributes(&mut self, file_dict: &BTreeMap<ByteString, Bencode>) -> Result<(), TorrentError>{
+        let attr: String = match file_dict.get(&ByteString::from_str("attr")){
+            Some(e) => FromBencode::from_bencode(e).unwrap_or(String::from("")),
+            _ => String::from("")
+        };
+
+        self.is_padding = attr.contains('p');
+        self.is_executable = attr.contains('x');
+        self.is_hidden = attr.contains('h');
+
+        if attr.contains('l'){
+            let symlink_path: Vec<String> = match file_dict.get(&ByteString::from_str("symlink path")){
+                Some(e) => {match FromBencode::from_bencode(e){Ok(e) => e, _ => return Err(TorrentError::new(format!("Malformed symlink path for {}", self.path)))}}
+                _ => return Err(TorrentError::new(format!("Symlink {} is missing its symlink path", self.path)))
+            };
+            self.symlink_path = Some(symlink_path.join("/"));
+        }
+
+        self.sha1 = match file_dict.get(&ByteString::from_str("sha1")){
+            Some(Bencode::ByteString(e)) => match e.as_slice().try_into(){
+                Ok(e) => Some(e),
+                _ => return Err(TorrentError::new(format!("Invalid sha1 for {}", self.path)))
+            },
+            _ => None
+        };
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TorrentInfo{
     pub files: Vec<TorrentFile>,
     pub announce_url: String,
-    pub alternative_announce_url: Vec<String>,
+    pub announce_list: AnnounceList,
+    pub url_list: Vec<String>,
+    pub http_seeds: Vec<String>,
     pub creation_date: u64,
     pub comment: String,
     pub creator: String,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
     pub hashes: Vec<Hash>,
     pub byte_size: u64,
     pub piece_byte_size: u64,
     pub num_pieces: usize,
-    pub info_hash: [u8; 20]
+    pub private: bool,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub info_hash: [u8; 20],
+    // the raw copies are the torrent file itself over again, ./neon dump shows every key of it
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub info_bytes: Vec<u8>,
+    // every top level entry exactly as it was read, so unknown keys survive an edit
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub raw_entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub meta_version: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub info_hash_v2: Option<HashV2>,
+    pub v2: Option<TorrentInfoV2>
 }
 
 impl TorrentInfo{
 
     fn process_single_file(file_info: &BTreeMap<ByteString, Bencode>) -> Result<Vec<TorrentFile>, TorrentError>{
-        let path: String = match file_info.get(&ByteString::from_str("name")){
-            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(format!("Unable to find the name of a file")))}}
+        let (raw_path, path) = match get_name(file_info, "name"){
+            Some(e) => e,
             _ => return Err(TorrentError::new(String::from("Unable to find name property for file")))
         };
 
@@ -46,19 +197,23 @@ impl TorrentInfo{
             _ => return Err(TorrentError::new(String::from("Unable to find name property for file")))
         };
 
+        let mut file = TorrentFile::new(path, size_bytes);
+        file.raw_path = vec![raw_path];
+        match file.process_attributes(file_info){
+            Ok(_) => (),
+            Err(e) => return Err(e)
+        };
+
         let mut ret: Vec<TorrentFile> = Vec::new();
-        ret.push(TorrentFile{
-            path,
-            size_bytes
-        });
+        ret.push(file);
 
         Ok(ret)
     }
 
     fn process_multi_file(file_info: &BTreeMap<ByteString, Bencode>) -> Result<Vec<TorrentFile>, TorrentError>{
         let mut ret: Vec<TorrentFile> = Vec::new();
-        let top_dir: String = match file_info.get(&ByteString::from_str("name")){
-            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(String::from("Unable to parse the directory name of a multi file torrent")))}}
+        let (raw_top_dir, top_dir) = match get_name(file_info, "name"){
+            Some(e) => e,
             _ => return Err(TorrentError::new(String::from("Unable to find the top directory name for the multi file spec")))
         };
         
@@ -83,17 +238,21 @@ impl TorrentInfo{
                 _ => return Err(TorrentError::new(String::from("Could not find length variable for file")))
             };
 
-            let path_vec: Vec<String> = match file_dict.get(&ByteString::from_str("path")){
-                Some(e) => {match FromBencode::from_bencode(e){Ok(e) => e, _ => return Err(TorrentError::new(String::from("Malformed file entry, invalid path")))}}
-                _ => return Err(TorrentError::new(String::from("Could not find path variable for file")))
+            let (raw_path_vec, path_vec) = match get_path(file_dict){
+                Some(e) => e,
+                _ => return Err(TorrentError::new(String::from("Could not find a valid path for file")))
             };
 
             let path: String = format!("{}/{}", top_dir, path_vec.join("/"));
-            
-            ret.push(TorrentFile{
-                path,
-                size_bytes
-            })
+
+            let mut file = TorrentFile::new(path, size_bytes);
+            file.raw_path = vec![raw_top_dir.clone()];
+            file.raw_path.extend(raw_path_vec);
+            match file.process_attributes(file_dict){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+            ret.push(file);
         };
 
         Ok(ret)
@@ -108,8 +267,329 @@ impl TorrentInfo{
         }
     }
 
+    fn process_info(&mut self, file_info: &BTreeMap<ByteString, Bencode>, piece_layers: Option<&BTreeMap<ByteString, Bencode>>) -> Result<(), TorrentError>{
+        self.meta_version = match file_info.get(&ByteString::from_str("meta version")){
+            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(String::from("Invalid meta version")))}}
+            _ => 1
+        };
+
+        if self.meta_version != 1 && self.meta_version != 2{
+            return Err(TorrentError::new(format!("Unsupported meta version {}", self.meta_version)));
+        }
+
+        // private torrents (BEP 27) may only get peers from their own trackers
+        self.private = match file_info.get(&ByteString::from_str("private")){
+            Some(e) => FromBencode::from_bencode(e).unwrap_or(0i64) == 1,
+            _ => false
+        };
+
+        self.piece_byte_size = match file_info.get(&ByteString::from_str("piece length")){
+            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(format!("Unable to find the piece length of a file")))}}
+            _ => return Err(TorrentError::new(String::from("Unable to find piece length property for file")))
+        };
+
+        if self.piece_byte_size == 0{
+            return Err(TorrentError::invalid(MetainfoError::ZeroPieceLength));
+        }
+        if !self.piece_byte_size.is_power_of_two(){
+            return Err(TorrentError::invalid(MetainfoError::PieceLengthNotPowerOfTwo(self.piece_byte_size)));
+        }
+
+        if self.meta_version == 2{
+            self.v2 = match TorrentInfoV2::from_info(file_info, piece_layers){
+                Ok(e) => Some(e),
+                Err(e) => return Err(e)
+            };
+        }
+
+        // v2 only torrents do not have v1 piece hashes, their pieces are verified with the merkle trees instead
+        if !file_info.contains_key(&ByteString::from_str("pieces")){
+            if let Some(v2) = &self.v2{
+                self.num_pieces = v2.num_pieces();
+                self.files = TorrentInfo::process_v2_files(file_info, v2);
+                sanitize_files(&mut self.files);
+                self.byte_size = self.files.iter().map(|e| e.size_bytes as u64).sum();
+                return Ok(());
+            }
+        }
+
+        let pieces: &Vec<u8> = match file_info.get(&ByteString::from_str("pieces")){
+            Some(e) => {match &e {
+                &Bencode::ByteString(b) => b,
+                _ => return Err(TorrentError::new(String::from("Unable to get the hashes as a byte array")))
+            }},
+            _ => return Err(TorrentError::new(String::from("Unable to find pieces property for file")))
+        };
+
+        if (pieces.len() % 20) != 0 {
+            return Err(TorrentError::invalid(MetainfoError::InvalidPiecesLength(pieces.len())));
+        }
+        else{
+            // a magnet link's hashes start out empty, never add to hashes left over from another parse
+            self.hashes.clear();
+            for i in 0..(pieces.len()/20){
+                self.hashes.push(match (&pieces[(i*20)..((i+1)*20)]).try_into(){
+                        Ok(e) => e,
+                        _ => return Err(TorrentError::new(String::from("Invalid slice length")))
+                    });
+            }
+        }
+
+        self.num_pieces = pieces.len() /20;
+
+        self.files = match TorrentInfo::process_files(file_info){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        sanitize_files(&mut self.files);
+
+        // the pieces cover every file back to back, padding files included
+        self.byte_size = self.files.iter().map(|e| e.size_bytes as u64).sum();
+        let expected = ((self.byte_size + self.piece_byte_size - 1) / self.piece_byte_size) as usize;
+        if expected != self.num_pieces{
+            return Err(TorrentError::invalid(MetainfoError::PieceCountMismatch{expected, found: self.num_pieces}));
+        }
+
+        if let Some(v2) = &self.v2{
+            if v2.num_pieces() != self.num_pieces{
+                return Err(TorrentError::new(format!("Hybrid torrent has {} v1 pieces but {} v2 pieces", self.num_pieces, v2.num_pieces())));
+            }
+
+            match self.check_hybrid_layout(&TorrentInfo::process_v2_files(file_info, v2)){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+        }
+
+        Ok(())
+    }
+
+    // the file tree does not include the torrent name, so add it as the top directory like the v1 multi file spec
+    fn process_v2_files(file_info: &BTreeMap<ByteString, Bencode>, v2: &TorrentInfoV2) -> Vec<TorrentFile>{
+        let (raw_name, name) = match get_name(file_info, "name"){
+            Some(e) => e,
+            _ => (Vec::new(), String::from(""))
+        };
+
+        let in_top_dir = !name.is_empty() && !(v2.files.len() == 1 && v2.files[0].raw_path.len() == 1);
+        v2.files.iter().map(|file|{
+            if !in_top_dir{
+                let mut ret = TorrentFile::new(file.path.clone(), file.size_bytes);
+                ret.raw_path = file.raw_path.clone();
+                return ret;
+            }
+
+            let mut ret = TorrentFile::new(format!("{}/{}", name, file.path), file.size_bytes);
+            ret.raw_path = vec![raw_name.clone()];
+            ret.raw_path.extend(file.raw_path.iter().cloned());
+            ret
+        }).collect()
+    }
+
+    // the v1 file list of a hybrid torrent must describe the same files as the file tree, with padding files
+    // between them so every file starts on a piece boundary
+    fn check_hybrid_layout(&self, v2_files: &Vec<TorrentFile>) -> Result<(), TorrentError>{
+        let mut v1_files = self.files.iter().peekable();
+        for (i, expected) in v2_files.iter().enumerate(){
+            let file = match v1_files.next(){
+                Some(e) if !e.is_padding => e,
+                _ => return Err(TorrentError::new(format!("Hybrid torrent is missing {} in its v1 file list", expected.path)))
+            };
+
+            if file.path != expected.path || file.size_bytes != expected.size_bytes{
+                return Err(TorrentError::new(format!("Hybrid torrent v1 file {} does not match v2 file {}", file.path, expected.path)));
+            }
+
+            let remainder = file.size_bytes as u64 % self.piece_byte_size;
+            if remainder == 0 || i == v2_files.len() - 1{
+                continue;
+            }
+
+            match v1_files.next(){
+                Some(pad) if pad.is_padding && pad.size_bytes as u64 == self.piece_byte_size - remainder => (),
+                _ => return Err(TorrentError::new(format!("Hybrid torrent is missing the padding after {}", file.path)))
+            };
+        }
+
+        // a trailing padding file after the last file is harmless
+        if v1_files.any(|e| !e.is_padding){
+            return Err(TorrentError::new(String::from("Hybrid torrent v1 file list has more files than the file tree")));
+        }
+
+        Ok(())
+    }
+
+    // problems that don't stop the torrent from downloading but are worth pointing out
+    pub fn validate(&self) -> Vec<String>{
+        let mut warnings: Vec<String> = Vec::new();
+
+        let mut paths: Vec<&String> = Vec::new();
+        for file in self.files.iter().filter(|e| !e.is_padding){
+            if file.disk_path != file.path{
+                warnings.push(format!("{} can't be written as is, saving it as {}", file.path, file.disk_path));
+            }
+
+            if paths.contains(&&file.path){
+                warnings.push(format!("{} appears more than once", file.path));
+            }
+            paths.push(&file.path);
+
+            if file.size_bytes == 0 && file.symlink_path.is_none(){
+                warnings.push(format!("{} is empty", file.path));
+            }
+        }
+
+        if self.num_pieces > HUGE_PIECE_COUNT{
+            warnings.push(format!("{} pieces is a lot, consider a bigger piece size than {} bytes", self.num_pieces, self.piece_byte_size));
+        }
+
+        warnings
+    }
+
+    pub fn is_single_file(&self) -> bool{
+        self.files.len() == 1 && !self.files[0].path.contains('/')
+    }
+
+    pub fn is_hybrid(&self) -> bool{
+        !self.hashes.is_empty() && self.info_hash_v2.is_some()
+    }
+
+    // every info hash this torrent can be found under on the wire
+    pub fn info_hashes(&self) -> Vec<[u8; 20]>{
+        let mut ret = vec![self.info_hash];
+        if let Some(hash) = self.info_hash_v2{
+            if truncate_hash(&hash) != self.info_hash{
+                ret.push(truncate_hash(&hash));
+            }
+        }
+        ret
+    }
+
+    fn set_info_hashes(&mut self, info_bytes: &[u8]){
+        if self.meta_version == 2{
+            self.info_hash_v2 = Some(sha256(info_bytes));
+        }
+
+        if self.hashes.is_empty() && self.info_hash_v2.is_some(){
+            self.info_hash = truncate_hash(&self.info_hash_v2.unwrap());
+        }
+        else{
+            let mut hasher = sha1::Sha1::new();
+            hasher.update(info_bytes);
+            self.info_hash = hasher.digest().bytes();
+        }
+    }
+
+    pub fn piece_size(&self, index: usize) -> u64{
+        if self.hashes.is_empty(){
+            if let Some(v2) = &self.v2{
+                return v2.piece_size(index);
+            }
+        }
+
+        self.piece_byte_size.min(self.byte_size - index as u64 * self.piece_byte_size)
+    }
+
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool{
+        if !self.hashes.is_empty(){
+            let mut hasher = sha1::Sha1::new();
+            hasher.update(data);
+            return hasher.digest().bytes() == self.hashes[index];
+        }
+
+        match &self.v2{
+            Some(v2) => v2.verify_piece(index, data),
+            _ => false
+        }
+    }
+
+    // a torrent created from a magnet link only knows its info hash until the metadata is fetched from peers
+    pub fn from_magnet(magnet: &MagnetLink) -> TorrentInfo{
+        let announce_url = magnet.trackers.first().cloned().unwrap_or_default();
+
+        TorrentInfo{
+            files: Vec::new(),
+            announce_url,
+            announce_list: AnnounceList::from_tiers(magnet.trackers.iter().map(|e| vec![e.clone()]).collect()),
+            url_list: magnet.web_seeds.clone(),
+            http_seeds: Vec::new(),
+            creation_date: 0,
+            comment: String::from("<No Comment>"),
+            creator: String::from("<Unknown Author>"),
+            hashes: Vec::new(),
+            byte_size: 0,
+            piece_byte_size: 0,
+            num_pieces: 0,
+            private: false,
+            info_hash: magnet.info_hash,
+            info_bytes: Vec::new(),
+            raw_entries: Vec::new(),
+            meta_version: 1,
+            info_hash_v2: magnet.info_hash_v2,
+            v2: None
+        }
+    }
+
+    pub fn has_metadata(&self) -> bool{
+        self.num_pieces != 0
+    }
+
+    // a v2 only torrent's info hash is its truncated SHA-256, so metadata is checked with SHA-256 whenever
+    // the full v2 hash is known and with SHA-1 otherwise
+    pub fn matches_metadata(&self, info_bytes: &[u8]) -> bool{
+        if let Some(hash) = self.info_hash_v2{
+            return sha256(info_bytes) == hash;
+        }
+
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(info_bytes);
+        hasher.digest().bytes() == self.info_hash
+    }
+
+    // fill in the info dictionary received from a peer, verifying it matches the info hash we already have
+    pub fn load_metadata(&mut self, info_bytes: &[u8]) -> Result<(), TorrentError>{
+        if !self.matches_metadata(info_bytes){
+            return Err(TorrentError::new(String::from("Metadata does not match the info hash")));
+        }
+
+        if bencode_raw::value_len(info_bytes) != Some(info_bytes.len()){
+            return Err(TorrentError::new(String::from("Metadata is not a valid info dictionary")));
+        }
+
+        let file_info: BTreeMap<ByteString, Bencode> = match bencode::from_buffer(info_bytes){
+            Ok(Bencode::Dict(e)) => e,
+            _ => return Err(TorrentError::new(String::from("Metadata is not a valid info dictionary")))
+        };
+
+        match self.process_info(&file_info, None){
+            Ok(_) => (),
+            Err(e) => return Err(e)
+        };
+
+        if self.meta_version == 2{
+            self.info_hash_v2 = Some(sha256(info_bytes));
+        }
+
+        self.info_bytes = info_bytes.to_vec();
+        self.set_raw_entry("info", Some(info_bytes.to_vec()));
+
+        // keep the trackers and web seeds from the magnet link if the torrent is saved
+        let tiers = self.announce_list.tiers.clone();
+        self.set_trackers(tiers);
+        if !self.url_list.is_empty(){
+            self.set_url_list(self.url_list.clone());
+        }
+        Ok(())
+    }
+
     pub fn from_buffer(buf: Vec<u8>) -> Result<TorrentInfo, TorrentError>{
-        let bencoding: bencode::Bencode = match bencode::from_vec(buf){
+        // the bencode crate recurses without a limit, so make sure the nesting is sane before handing it over
+        if bencode_raw::value_len(&buf).is_none(){
+            return Err(TorrentError::new(String::from("Unable to parse torrent file")));
+        }
+
+        let bencoding: bencode::Bencode = match bencode::from_buffer(&buf){
             Ok(e) => e,
             Err(_e) => return Err(TorrentError::new(String::from(format!("Unable to parse torrent file"))))
         };
@@ -122,7 +602,9 @@ impl TorrentInfo{
         let mut ret: TorrentInfo = TorrentInfo{
             files: Vec::new(),
             announce_url: String::from(""),
-            alternative_announce_url: Vec::new(),
+            announce_list: AnnounceList::default(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
             creation_date: 0,
             comment: String::from(""),
             creator: String::from(""),
@@ -130,7 +612,13 @@ impl TorrentInfo{
             byte_size: 0,
             piece_byte_size: 0,
             num_pieces: 0,
-            info_hash: [0; 20]
+            private: false,
+            info_hash: [0; 20],
+            info_bytes: Vec::new(),
+            raw_entries: Vec::new(),
+            meta_version: 1,
+            info_hash_v2: None,
+            v2: None
         };
 
         ret.announce_url = match torrent_info.get(&ByteString::from_str("announce")){
@@ -138,7 +626,7 @@ impl TorrentInfo{
                 Ok(b) => b,
                 _ => return Err(TorrentError::new(String::from("Invalid announce url")))
             }},
-            _ => return Err(TorrentError::new(String::from("Unable to find announce_url")))
+            _ => String::from("")
         };
 
         ret.comment = match torrent_info.get(&ByteString::from_str("comment")){
@@ -157,17 +645,25 @@ impl TorrentInfo{
             _ => String::from("<Unknown Author>")
         };
 
-        let announce_list: Vec<String> = match torrent_info.get(&ByteString::from_str("announce-list")){
-            Some(a) => {match FromBencode::from_bencode(a){
-                Ok(b) => b,
-                _ => Vec::new()
-            }},
+        ret.announce_list = AnnounceList::new(&ret.announce_url, torrent_info.get(&ByteString::from_str("announce-list")));
+        if ret.announce_list.is_empty(){
+            return Err(TorrentError::new(String::from("Unable to find announce_url")));
+        }
+        ret.announce_list.shuffle();
+
+        // url-list may be a single url or a list of them (BEP 19)
+        ret.url_list = match torrent_info.get(&ByteString::from_str("url-list")){
+            Some(Bencode::List(e)) => e.iter().filter_map(|url| FromBencode::from_bencode(url).ok()).collect(),
+            Some(e) => FromBencode::from_bencode(e).map(|url| vec![url]).unwrap_or(Vec::new()),
             _ => Vec::new()
         };
+        ret.url_list.retain(|url: &String| !url.is_empty());
 
-        // TODO: remove duplicates from the announce url list and the announce url
-
-        ret.alternative_announce_url = announce_list;
+        ret.http_seeds = match torrent_info.get(&ByteString::from_str("httpseeds")){
+            Some(Bencode::List(e)) => e.iter().filter_map(|url| FromBencode::from_bencode(url).ok()).collect(),
+            _ => Vec::new()
+        };
+        ret.http_seeds.retain(|url: &String| !url.is_empty());
 
         ret.creation_date = match torrent_info.get(&ByteString::from_str("creation date")){
             Some(a) => {match FromBencode::from_bencode(a){
@@ -186,41 +682,27 @@ impl TorrentInfo{
             _ => return Err(TorrentError::new(String::from("Unable to find torrent file info")))
         };
 
-        let mut info_bytes= Bencode::Dict(file_info.clone()).to_bytes().unwrap();
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&mut info_bytes);
-        ret.info_hash = hasher.digest().bytes();
-
-        ret.piece_byte_size = match file_info.get(&ByteString::from_str("piece length")){
-            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(format!("Unable to find the piece length of a file")))}}
-            _ => return Err(TorrentError::new(String::from("Unable to find piece length property for file")))
+        let piece_layers: Option<&BTreeMap<ByteString, Bencode>> = match torrent_info.get(&ByteString::from_str("piece layers")){
+            Some(Bencode::Dict(e)) => Some(e),
+            _ => None
         };
 
-        let pieces: &Vec<u8> = match file_info.get(&ByteString::from_str("pieces")){
-            Some(e) => {match &e {
-                &Bencode::ByteString(b) => b,
-                _ => return Err(TorrentError::new(String::from("Unable to get the hashes as a byte array")))
-            }},
-            _ => return Err(TorrentError::new(String::from("Unable to find pieces property for file")))
+        match ret.process_info(file_info, piece_layers){
+            Ok(_) => (),
+            Err(e) => return Err(e)
         };
 
-        if (pieces.len() % 20) != 0 {
-            return Err(TorrentError::new(String::from("Unable to find pieces property for file")));
-        }
-        else{
-            for i in 0..(pieces.len()/20){
-                ret.hashes.push(match (&pieces[(i*20)..((i+1)*20)]).try_into(){
-                        Ok(e) => e,
-                        _ => return Err(TorrentError::new(String::from("Invalid slice length")))
-                    });
-            }
-        }
-
-        ret.num_pieces = pieces.len() /20;
-        ret.byte_size = ret.piece_byte_size * ret.num_pieces as u64;
+        // hash the info dict exactly as it appears in the file, re-encoding it would change the hash of non canonical torrents
+        ret.info_bytes = match bencode_raw::dict_value(&buf, "info"){
+            Ok(Some(e)) => e.to_vec(),
+            Ok(None) => return Err(TorrentError::new(String::from("Unable to find torrent file info"))),
+            Err(e) => return Err(e)
+        };
+        let info_bytes = ret.info_bytes.clone();
+        ret.set_info_hashes(&info_bytes);
 
-        ret.files = match TorrentInfo::process_files(file_info){
-            Ok(e) => e,
+        ret.raw_entries = match bencode_raw::dict_entries(&buf){
+            Ok(e) => e.into_iter().map(|(key, range)| (key.as_slice().to_vec(), buf[range].to_vec())).collect(),
             Err(e) => return Err(e)
         };
 
@@ -240,4 +722,186 @@ impl TorrentInfo{
 
         Ok(torrent_info)
     }
+
+    // replace, add or remove (None) a top level entry, new keys are kept in sorted order
+    fn set_raw_entry(&mut self, key: &str, value: Option<Vec<u8>>){
+        let key = key.as_bytes().to_vec();
+        let index = self.raw_entries.iter().position(|(k, _)| *k == key);
+
+        match (index, value){
+            (Some(i), Some(value)) => self.raw_entries[i].1 = value,
+            (Some(i), None) => {self.raw_entries.remove(i);},
+            (None, Some(value)) => {
+                let position = self.raw_entries.iter().position(|(k, _)| *k > key).unwrap_or(self.raw_entries.len());
+                self.raw_entries.insert(position, (key, value));
+            },
+            (None, None) => ()
+        }
+    }
+
+    fn encode_strings(values: &Vec<String>) -> Vec<u8>{
+        Bencode::List(values.iter().map(|e| Bencode::ByteString(e.as_bytes().to_vec())).collect()).to_bytes().unwrap()
+    }
+
+    // the first tracker becomes the announce url, and the announce-list is only kept when there is more than one tracker
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>){
+        self.announce_list = AnnounceList::from_tiers(tiers);
+        let urls = self.announce_list.urls();
+        self.announce_url = urls.first().cloned().unwrap_or_default();
+
+        let announce = if self.announce_url.is_empty() { None } else { Some(Bencode::ByteString(self.announce_url.as_bytes().to_vec()).to_bytes().unwrap()) };
+        self.set_raw_entry("announce", announce);
+
+        let announce_list = if urls.len() > 1 {
+            Some(Bencode::List(self.announce_list.tiers.iter().map(|tier| Bencode::List(tier.iter().map(|e| Bencode::ByteString(e.as_bytes().to_vec())).collect())).collect()).to_bytes().unwrap())
+        } else {
+            None
+        };
+        self.set_raw_entry("announce-list", announce_list);
+    }
+
+    pub fn set_comment(&mut self, comment: &str){
+        self.comment = comment.to_string();
+        let value = if comment.is_empty() { None } else { Some(Bencode::ByteString(comment.as_bytes().to_vec()).to_bytes().unwrap()) };
+        self.set_raw_entry("comment", value);
+    }
+
+    pub fn set_creator(&mut self, creator: &str){
+        self.creator = creator.to_string();
+        let value = if creator.is_empty() { None } else { Some(Bencode::ByteString(creator.as_bytes().to_vec()).to_bytes().unwrap()) };
+        self.set_raw_entry("created by", value);
+    }
+
+    pub fn set_creation_date(&mut self, creation_date: u64){
+        self.creation_date = creation_date;
+        let value = if creation_date == 0 { None } else { Some(Bencode::Number(creation_date as i64).to_bytes().unwrap()) };
+        self.set_raw_entry("creation date", value);
+    }
+
+    pub fn set_url_list(&mut self, url_list: Vec<String>){
+        self.url_list = url_list;
+        let value = if self.url_list.is_empty() { None } else { Some(TorrentInfo::encode_strings(&self.url_list)) };
+        self.set_raw_entry("url-list", value);
+    }
+
+    pub fn add_web_seed(&mut self, url: &str){
+        if !self.url_list.iter().any(|e| e == url){
+            let mut url_list = self.url_list.clone();
+            url_list.push(url.to_string());
+            self.set_url_list(url_list);
+        }
+    }
+
+    pub fn set_http_seeds(&mut self, http_seeds: Vec<String>){
+        self.http_seeds = http_seeds;
+        let value = if self.http_seeds.is_empty() { None } else { Some(TorrentInfo::encode_strings(&self.http_seeds)) };
+        self.set_raw_entry("httpseeds", value);
+    }
+
+    // the torrent file with only the edited entries changed, the info dict is never touched so the info hash stays the same
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut ret: Vec<u8> = vec![b'd'];
+        for (key, value) in self.raw_entries.iter(){
+            ret.extend(key.len().to_string().as_bytes());
+            ret.push(b':');
+            ret.extend(key);
+            ret.extend(value);
+        }
+        ret.push(b'e');
+        ret
+    }
+
+    pub fn write(&self, name: &str) -> Result<(), TorrentError>{
+        match std::fs::write(name, self.to_bytes()){
+            Ok(_) => Ok(()),
+            Err(e) => Err(TorrentError::new(format!("Unable to write {}: {}", name, e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod torrent_file_tests {
+    use crate::magnet::MagnetLink;
+    use crate::torrent_builder::{TestDir, TorrentVersion};
+    use crate::torrent_file::TorrentInfo;
+    use crate::utils::MetainfoError;
+
+    #[test]
+    fn test_edit_round_trip(){
+        // unsorted keys, an unknown top level key and an unknown info key all have to survive
+        let buf = b"d8:announce14:http://tracker7:comment3:old4:infod6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384e7:unknowni1ee9:x-unknownl1:a1:bee".to_vec();
+        let mut info = TorrentInfo::from_buffer(buf.clone()).unwrap();
+        assert_eq!(info.to_bytes(), buf);
+
+        let info_hash = info.info_hash;
+        info.set_comment("new");
+        info.add_web_seed("http://mirror/a.txt");
+        info.set_trackers(vec![vec![String::from("udp://a:80")], vec![String::from("http://tracker")]]);
+
+        let edited = info.to_bytes();
+        assert_eq!(edited, b"d8:announce10:udp://a:8013:announce-listll10:udp://a:80el14:http://trackeree7:comment3:new4:infod6:lengthi5e4:name5:a.txt6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16384e7:unknowni1ee8:url-listl19:http://mirror/a.txte9:x-unknownl1:a1:bee".to_vec());
+
+        let info = TorrentInfo::from_buffer(edited).unwrap();
+        assert_eq!(info.info_hash, info_hash);
+        assert_eq!(info.url_list, vec![String::from("http://mirror/a.txt")]);
+    }
+
+    #[test]
+    fn test_legacy_names(){
+        // latin-1 names from an old client, only the first file has a utf-8 variant
+        let mut buf = b"d8:announce14:http://tracker4:infod5:filesld6:lengthi1e4:pathl4:caf\xe9e10:path.utf-8l5:caf\xc3\xa9eed6:lengthi1e4:pathl3:\xe9t\xe9eee4:name2:d\xe912:piece lengthi16384e6:pieces20:".to_vec();
+        buf.extend(&[0u8; 20]);
+        buf.extend(b"ee");
+
+        let info = TorrentInfo::from_buffer(buf).unwrap();
+        assert_eq!(info.files[0].path, "d%E9/caf\u{e9}");
+        assert_eq!(info.files[0].raw_path, vec![b"d\xe9".to_vec(), b"caf\xe9".to_vec()]);
+        assert_eq!(info.files[1].path, "d%E9/%E9t%E9");
+    }
+
+    #[test]
+    fn test_validation(){
+        let torrent = |length: &str, piece_length: &str| format!("d8:announce14:http://tracker4:infod5:filesld6:lengthi{}e4:pathl5:a.txteed6:lengthi0e4:pathl5:a.txteee4:name1:d6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa12:piece lengthi{}eee", length, piece_length).into_bytes();
+
+        let info = TorrentInfo::from_buffer(torrent("20000", "16384")).unwrap();
+        assert_eq!(info.byte_size, 20000);
+        assert_eq!(info.piece_size(1), 20000 - 16384);
+        assert_eq!(info.validate(), vec![String::from("d/a.txt can't be written as is, saving it as d/a_1.txt"), String::from("d/a.txt appears more than once"), String::from("d/a.txt is empty")]);
+
+        assert_eq!(TorrentInfo::from_buffer(torrent("20000", "0")).unwrap_err().reason, Some(MetainfoError::ZeroPieceLength));
+        assert_eq!(TorrentInfo::from_buffer(torrent("20000", "10000")).unwrap_err().reason, Some(MetainfoError::PieceLengthNotPowerOfTwo(10000)));
+        assert_eq!(TorrentInfo::from_buffer(torrent("40000", "16384")).unwrap_err().reason, Some(MetainfoError::PieceCountMismatch{expected: 3, found: 2}));
+    }
+
+    #[test]
+    fn test_v2_metadata(){
+        let dir = TestDir::new("torrent-file-v2", &[("a.bin", vec![5u8; 1000])]);
+        let info = dir.torrent("a.bin", |e| e.announce("http://tracker.example.com/announce").version(TorrentVersion::V2));
+
+        let v2 = info.info_hash_v2.unwrap();
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220{}", v2.iter().map(|e| format!("{:02x}", e)).collect::<String>())).unwrap();
+        let mut from_magnet = TorrentInfo::from_magnet(&magnet);
+        assert_eq!(from_magnet.info_hash, info.info_hash);
+
+        let mut tampered = info.info_bytes.clone();
+        let last = tampered.len() - 2;
+        tampered[last] ^= 1;
+        assert!(from_magnet.load_metadata(&tampered).is_err());
+
+        from_magnet.load_metadata(&info.info_bytes).unwrap();
+        assert_eq!(from_magnet.num_pieces, 1);
+        assert_eq!(from_magnet.info_hash_v2, Some(v2));
+    }
+
+    #[test]
+    fn test_metadata_loaded_twice(){
+        let dir = TestDir::new("torrent-file-reload", &[("a.bin", vec![5u8; 40000])]);
+        let info = dir.torrent("a.bin", |e| e.piece_size(16384).announce("http://tracker.example.com/announce"));
+
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", info.info_hash.iter().map(|e| format!("{:02x}", e)).collect::<String>())).unwrap();
+        let mut from_magnet = TorrentInfo::from_magnet(&magnet);
+        from_magnet.load_metadata(&info.info_bytes).unwrap();
+        from_magnet.load_metadata(&info.info_bytes).unwrap();
+        assert_eq!(from_magnet.hashes, info.hashes);
+    }
 }
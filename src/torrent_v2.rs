@@ -0,0 +1,389 @@
+extern crate sha2;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use bencode::{Bencode, FromBencode};
+use bencode::util::ByteString;
+use sha2::{Digest, Sha256};
+use crate::utils::TorrentError;
+use crate::torrent_file::decode_name;
+
+pub type HashV2 = [u8; 32];
+
+// v2 torrents hash files in 16KiB blocks which form the leaves of each file's merkle tree
+pub const BLOCK_SIZE: usize = 16384;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TorrentFileV2{
+    pub path: String,
+    // the path components exactly as they appear in the file tree
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub raw_path: Vec<Vec<u8>>,
+    pub size_bytes: usize,
+    #[cfg_attr(feature = "serde", serde(with = "crate::hex"))]
+    pub pieces_root: Option<HashV2>
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TorrentInfoV2{
+    pub meta_version: u64,
+    pub files: Vec<TorrentFileV2>,
+    // json can't have hashes as keys, the layers can always be read from the torrent again
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub piece_layers: BTreeMap<HashV2, Vec<HashV2>>,
+    pub piece_byte_size: u64
+}
+
+pub fn sha256(data: &[u8]) -> HashV2{
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+// v2 info hashes are truncated to 20 bytes wherever a v1 sized hash is expected (handshakes, trackers)
+pub fn truncate_hash(hash: &HashV2) -> [u8; 20]{
+    hash[..20].try_into().unwrap()
+}
+
+fn hash_pair(left: &HashV2, right: &HashV2) -> HashV2{
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// the root of a subtree with 2^levels leaves that are all zero hashes
+pub fn pad_hash(levels: u32) -> HashV2{
+    let mut hash = [0u8; 32];
+    for _ in 0..levels{
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+// reduce a layer of the tree to its root, padding it to `width` entries (a power of two) with `pad`
+pub fn merkle_root(mut layer: Vec<HashV2>, width: usize, pad: HashV2) -> HashV2{
+    layer.resize(width.max(1), pad);
+
+    while layer.len() > 1{
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+    }
+
+    layer[0]
+}
+
+// hashes of every 16KiB block of the data, the last block may be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<HashV2>{
+    data.chunks(BLOCK_SIZE).map(|block| sha256(block)).collect()
+}
+
+pub fn blocks_per_piece(piece_byte_size: u64) -> usize{
+    (piece_byte_size as usize / BLOCK_SIZE).max(1)
+}
+
+fn get_dict<'a>(dict: &'a BTreeMap<ByteString, Bencode>, key: &str) -> Option<&'a BTreeMap<ByteString, Bencode>>{
+    match dict.get(&ByteString::from_str(key)){
+        Some(Bencode::Dict(e)) => Some(e),
+        _ => None
+    }
+}
+
+fn to_hash(bytes: &[u8]) -> Result<HashV2, TorrentError>{
+    match bytes.try_into(){
+        Ok(e) => Ok(e),
+        _ => Err(TorrentError::new(String::from("Invalid SHA-256 hash length")))
+    }
+}
+
+impl TorrentInfoV2{
+
+    // the file tree maps each path element to either another directory or, under the "" key, a file entry
+    fn process_file_tree(tree: &BTreeMap<ByteString, Bencode>, raw_path: &mut Vec<Vec<u8>>, files: &mut Vec<TorrentFileV2>) -> Result<(), TorrentError>{
+        for (name, node) in tree{
+            let node: &BTreeMap<ByteString, Bencode> = match node{
+                Bencode::Dict(e) => e,
+                _ => return Err(TorrentError::new(String::from("Malformed entry in the file tree")))
+            };
+
+            if name.as_slice().is_empty(){
+                let path = raw_path.iter().map(|e| decode_name(e)).collect::<Vec<String>>().join("/");
+                let size_bytes: usize = match node.get(&ByteString::from_str("length")){
+                    Some(e) => {match FromBencode::from_bencode(e){Ok(e) => e, _ => return Err(TorrentError::new(String::from("Malformed file tree entry, invalid length")))}}
+                    _ => return Err(TorrentError::new(String::from("Could not find length variable for file tree entry")))
+                };
+
+                let pieces_root: Option<HashV2> = match node.get(&ByteString::from_str("pieces root")){
+                    Some(Bencode::ByteString(e)) => match to_hash(e){
+                        Ok(e) => Some(e),
+                        Err(e) => return Err(e)
+                    },
+                    Some(_) => return Err(TorrentError::new(String::from("Malformed file tree entry, invalid pieces root"))),
+                    _ => None
+                };
+
+                if size_bytes > 0 && pieces_root.is_none(){
+                    return Err(TorrentError::new(format!("File {} is missing its pieces root", path)));
+                }
+
+                files.push(TorrentFileV2{
+                    path,
+                    raw_path: raw_path.clone(),
+                    size_bytes,
+                    pieces_root
+                });
+                continue;
+            }
+
+            raw_path.push(name.as_slice().to_vec());
+            match TorrentInfoV2::process_file_tree(node, raw_path, files){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+            raw_path.pop();
+        }
+
+        Ok(())
+    }
+
+    fn process_piece_layers(&mut self, piece_layers: &BTreeMap<ByteString, Bencode>) -> Result<(), TorrentError>{
+        let blocks = blocks_per_piece(self.piece_byte_size);
+
+        for file in self.files.iter(){
+            // files no larger than a piece are verified against their pieces root directly
+            if file.size_bytes as u64 <= self.piece_byte_size{
+                continue;
+            }
+
+            let root = file.pieces_root.unwrap();
+            let layer: &Vec<u8> = match piece_layers.get(&ByteString::from_slice(&root)){
+                Some(Bencode::ByteString(e)) => e,
+                _ => return Err(TorrentError::new(format!("Missing piece layer for {}", file.path)))
+            };
+
+            let num_pieces = (file.size_bytes as u64 + self.piece_byte_size - 1) / self.piece_byte_size;
+            if layer.len() != num_pieces as usize * 32{
+                return Err(TorrentError::new(format!("Piece layer for {} has the wrong length", file.path)));
+            }
+
+            let hashes: Vec<HashV2> = layer.chunks(32).map(|e| e.try_into().unwrap()).collect();
+            if merkle_root(hashes.clone(), hashes.len().next_power_of_two(), pad_hash(blocks.trailing_zeros())) != root{
+                return Err(TorrentError::new(format!("Piece layer for {} does not match its pieces root", file.path)));
+            }
+
+            self.piece_layers.insert(root, hashes);
+        }
+
+        Ok(())
+    }
+
+    pub fn from_info(file_info: &BTreeMap<ByteString, Bencode>, piece_layers: Option<&BTreeMap<ByteString, Bencode>>) -> Result<TorrentInfoV2, TorrentError>{
+        let mut ret = TorrentInfoV2{
+            meta_version: 2,
+            files: Vec::new(),
+            piece_layers: BTreeMap::new(),
+            piece_byte_size: 0
+        };
+
+        ret.piece_byte_size = match file_info.get(&ByteString::from_str("piece length")){
+            Some(e) => {match FromBencode::from_bencode(e){Ok(b) => b, _ => return Err(TorrentError::new(format!("Unable to find the piece length of a file")))}}
+            _ => return Err(TorrentError::new(String::from("Unable to find piece length property for file")))
+        };
+
+        if ret.piece_byte_size < BLOCK_SIZE as u64 || !ret.piece_byte_size.is_power_of_two(){
+            return Err(TorrentError::new(format!("Invalid piece length for a v2 torrent: {}", ret.piece_byte_size)));
+        }
+
+        let file_tree = match get_dict(file_info, "file tree"){
+            Some(e) => e,
+            _ => return Err(TorrentError::new(String::from("Unable to find the file tree of a v2 torrent")))
+        };
+
+        match TorrentInfoV2::process_file_tree(file_tree, &mut Vec::new(), &mut ret.files){
+            Ok(_) => (),
+            Err(e) => return Err(e)
+        };
+
+        if ret.files.is_empty(){
+            return Err(TorrentError::new(String::from("File tree does not contain any files")));
+        }
+
+        // piece layers live outside the info dict, so they are unavailable until fetched when started from a magnet link
+        if let Some(layers) = piece_layers{
+            match ret.process_piece_layers(layers){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
+        }
+
+        Ok(ret)
+    }
+
+    // files bigger than a piece need their piece layer, which is outside the info dict and so missing
+    // when the metadata came from peers
+    pub fn has_piece_layers(&self) -> bool{
+        self.files.iter().all(|file| file.size_bytes as u64 <= self.piece_byte_size || self.piece_layers.contains_key(&file.pieces_root.unwrap()))
+    }
+
+    fn file_pieces(&self, file: &TorrentFileV2) -> usize{
+        ((file.size_bytes as u64 + self.piece_byte_size - 1) / self.piece_byte_size) as usize
+    }
+
+    pub fn num_pieces(&self) -> usize{
+        self.files.iter().map(|file| self.file_pieces(file)).sum()
+    }
+
+    // pieces never span files in v2, returns the file and the index of the piece within that file
+    pub fn piece_location(&self, index: usize) -> Option<(&TorrentFileV2, usize)>{
+        let mut first_piece = 0;
+        for file in self.files.iter(){
+            let count = self.file_pieces(file);
+            if index < first_piece + count{
+                return Some((file, index - first_piece));
+            }
+            first_piece += count;
+        }
+
+        None
+    }
+
+    pub fn piece_size(&self, index: usize) -> u64{
+        match self.piece_location(index){
+            Some((file, piece)) => self.piece_byte_size.min(file.size_bytes as u64 - piece as u64 * self.piece_byte_size),
+            _ => 0
+        }
+    }
+
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool{
+        let (file, piece) = match self.piece_location(index){
+            Some(e) => e,
+            _ => return false
+        };
+
+        let root = match file.pieces_root{
+            Some(e) => e,
+            _ => return false
+        };
+
+        let hashes = block_hashes(data);
+        if file.size_bytes as u64 <= self.piece_byte_size{
+            let width = hashes.len().next_power_of_two();
+            return merkle_root(hashes, width, [0; 32]) == root;
+        }
+
+        match self.piece_layers.get(&root){
+            Some(layer) => merkle_root(hashes, blocks_per_piece(self.piece_byte_size), [0; 32]) == layer[piece],
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod merkle_tests {
+    use crate::torrent_v2::{merkle_root, pad_hash, block_hashes, sha256, hash_pair, BLOCK_SIZE};
+
+    #[test]
+    fn test_merkle_padding(){
+        let data = vec![7u8; BLOCK_SIZE * 3];
+        let hashes = block_hashes(&data);
+        let expected = hash_pair(&hash_pair(&hashes[0], &hashes[1]), &hash_pair(&hashes[2], &[0; 32]));
+
+        assert_eq!(merkle_root(hashes, 4, [0; 32]), expected);
+        assert_eq!(pad_hash(1), sha256(&[0u8; 64]));
+    }
+}
+
+#[cfg(test)]
+mod parse_tests {
+    use std::collections::BTreeMap;
+
+    use bencode::Bencode;
+    use bencode::util::ByteString;
+    use crate::torrent_v2::{TorrentInfoV2, HashV2, block_hashes, merkle_root, pad_hash, BLOCK_SIZE};
+
+    fn dict(entries: Vec<(&str, Bencode)>) -> BTreeMap<ByteString, Bencode>{
+        entries.into_iter().map(|(key, value)| (ByteString::from_str(key), value)).collect()
+    }
+
+    fn file(length: i64, pieces_root: Option<Vec<u8>>) -> Bencode{
+        let mut entry = vec![("length", Bencode::Number(length))];
+        if let Some(root) = pieces_root{
+            entry.push(("pieces root", Bencode::ByteString(root)));
+        }
+        Bencode::Dict(dict(vec![("", Bencode::Dict(dict(entry)))]))
+    }
+
+    fn info(piece_length: i64, tree: BTreeMap<ByteString, Bencode>) -> BTreeMap<ByteString, Bencode>{
+        dict(vec![("piece length", Bencode::Number(piece_length)), ("file tree", Bencode::Dict(tree))])
+    }
+
+    #[test]
+    fn test_file_tree(){
+        let tree = dict(vec![
+            ("dir", Bencode::Dict(dict(vec![("a.bin", file(100, Some(vec![1; 32])))]))),
+            ("empty", file(0, None))
+        ]);
+
+        let v2 = TorrentInfoV2::from_info(&info(16384, tree), None).unwrap();
+        assert_eq!(v2.files.iter().map(|e| (e.path.as_str(), e.size_bytes)).collect::<Vec<(&str, usize)>>(), vec![("dir/a.bin", 100), ("empty", 0)]);
+        assert_eq!(v2.files[0].pieces_root, Some([1; 32]));
+        assert_eq!(v2.files[1].pieces_root, None);
+    }
+
+    #[test]
+    fn test_raw_path(){
+        let mut tree = BTreeMap::new();
+        tree.insert(ByteString::from_vec(b"caf\xe9".to_vec()), Bencode::Dict(dict(vec![("a%41/b", file(100, Some(vec![1; 32])))])));
+
+        // the names are kept as they are, the escaped path is only for display
+        let v2 = TorrentInfoV2::from_info(&info(16384, tree), None).unwrap();
+        assert_eq!(v2.files[0].path, "caf%E9/a%41/b");
+        assert_eq!(v2.files[0].raw_path, vec![b"caf\xe9".to_vec(), b"a%41/b".to_vec()]);
+    }
+
+    #[test]
+    fn test_file_tree_errors(){
+        let single = |entry: Bencode| dict(vec![("a.bin", entry)]);
+
+        // the tree itself, the piece length and the entries are all checked
+        assert!(TorrentInfoV2::from_info(&dict(vec![("piece length", Bencode::Number(16384))]), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, BTreeMap::new()), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(10000, single(file(100, Some(vec![1; 32])))), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(8192, single(file(100, Some(vec![1; 32])))), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, single(Bencode::Number(1))), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, single(Bencode::Dict(dict(vec![("", Bencode::Dict(BTreeMap::new()))])))), None).is_err());
+
+        // pieces root has to be 32 bytes, and only empty files can leave it out
+        assert!(TorrentInfoV2::from_info(&info(16384, single(file(100, Some(vec![1; 31])))), None).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, single(file(100, None))), None).is_err());
+    }
+
+    #[test]
+    fn test_piece_layers(){
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        // with 16KiB pieces every piece is one block, so the layer is just the block hashes
+        let layer: Vec<HashV2> = block_hashes(&data);
+        let root = merkle_root(layer.clone(), 4, pad_hash(0));
+        let tree = dict(vec![("a.bin", file(data.len() as i64, Some(root.to_vec())))]);
+        let with_layer = |layer: Bencode|{
+            let mut layers = BTreeMap::new();
+            layers.insert(ByteString::from_slice(&root), layer);
+            layers
+        };
+
+        let v2 = TorrentInfoV2::from_info(&info(16384, tree.clone()), Some(&with_layer(Bencode::ByteString(layer.concat())))).unwrap();
+        assert!(v2.has_piece_layers());
+        assert!(v2.verify_piece(1, &data[BLOCK_SIZE..BLOCK_SIZE * 2]));
+        assert!(!v2.verify_piece(1, &data[..BLOCK_SIZE]));
+
+        // without layers the metadata still parses, it just can't verify pieces yet
+        assert!(!TorrentInfoV2::from_info(&info(16384, tree.clone()), None).unwrap().has_piece_layers());
+
+        assert!(TorrentInfoV2::from_info(&info(16384, tree.clone()), Some(&BTreeMap::new())).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, tree.clone()), Some(&with_layer(Bencode::ByteString(layer[..2].concat())))).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, tree.clone()), Some(&with_layer(Bencode::ByteString(vec![0; 96])))).is_err());
+        assert!(TorrentInfoV2::from_info(&info(16384, tree), Some(&with_layer(Bencode::Number(1)))).is_err());
+    }
+}
@@ -1,36 +1,161 @@
-extern crate clokwerk;
 extern crate rand;
 
 use crate::torrent::Torrent;
+use crate::announce_list::AnnounceList;
+use crate::bencode_raw;
 
-use std::sync::{Mutex, Arc};
-use std::fmt::{Debug, Formatter};
-
-use self::clokwerk::{ScheduleHandle};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::io;
+use std::io::Write;
 
+use colored::Colorize;
+use crossbeam_channel::Sender;
 use percent_encoding::percent_encode_byte;
 use std::collections::BTreeMap;
 use bencode::{Bencode, FromBencode};
 use bencode::util::ByteString;
 
-use crate::peers::Peer;
-use crate::utils::{TorrentError, u32_to_bytes, u64_to_bytes, bytes_to_u32, bytes_to_u64, u16_to_bytes, i64_to_bytes, i32_to_bytes, bytes_to_i32, bytes_to_i64, bytes_to_u16};
+use crate::utils::{TorrentError, TransferStats, u32_to_bytes, u64_to_bytes, bytes_to_u32, bytes_to_u64, u16_to_bytes, i64_to_bytes, i32_to_bytes, bytes_to_i32, bytes_to_i64, bytes_to_u16};
 use self::rand::{Rng, RngCore};
 use minreq::Method::Connect;
-use std::net::{UdpSocket, ToSocketAddrs, Ipv4Addr};
-use std::time::Duration;
+use std::net::{UdpSocket, ToSocketAddrs, Ipv4Addr, Ipv6Addr, IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use dns_lookup::lookup_host;
 use std::convert::TryInto;
 
-type TorrentMutex = Arc<Mutex<Torrent>>;
+macro_rules! thread_println {
+    ($( $args:expr ),*) => {
+        writeln!(&mut io::stdout().lock(), $( $args ),* ).expect("Cannot write to stdout");
+    }
+}
+
+// the longest shutting down waits for the trackers to hear stopped
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(10);
+// used when a tracker doesn't send an interval, and the least we will wait no matter what it asks for
+const DEFAULT_INTERVAL: u64 = 1800;
+const MIN_INTERVAL: u64 = 30;
+// how soon to try again when none of the trackers responded
+const RETRY_INTERVAL: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AnnounceEvent{
+    None,
+    Started,
+    Completed,
+    Stopped
+}
+
+impl AnnounceEvent{
+    // the event field of a UDP announce
+    fn udp_id(&self) -> i32{
+        match self{
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3
+        }
+    }
+
+    // the event parameter of an HTTP announce, left out for regular announces
+    fn http_name(&self) -> Option<&'static str>{
+        match self{
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TrackerPeer{
+    pub ip: String,
+    pub port: u16,
+    // only trackers using the dictionary model tell us who to expect at the address
+    pub peer_id: Option<Vec<u8>>,
+    // the info hash the peer was announced under, a hybrid torrent's v2 swarm only knows the truncated v2 hash
+    pub info_hash: [u8; 20]
+}
+
+// when a tracker that refused an announce wants to hear from us again (BEP 31)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RetryIn{
+    Minutes(u64),
+    Never
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceResponse{
+    pub peers: Vec<TrackerPeer>,
+    pub interval: u64,
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<Vec<u8>>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    // messages from trackers that answered but want to tell the user something, with the tracker's url
+    pub warnings: Vec<(String, String)>,
+    // set when the tracker refused the announce, nothing else is filled in then
+    pub failure_reason: Option<String>,
+    pub retry_in: Option<RetryIn>
+}
+
+impl AnnounceResponse{
+    fn empty() -> AnnounceResponse{
+        AnnounceResponse::default()
+    }
+
+    // wait as long as the slowest tracker wants us to and only keep one peer per address
+    fn merge(&mut self, other: AnnounceResponse){
+        self.interval = self.interval.max(other.interval);
+        self.min_interval = self.min_interval.max(other.min_interval);
+        self.seeders = self.seeders.max(other.seeders);
+        self.leechers = self.leechers.max(other.leechers);
+        self.warnings.extend(other.warnings);
+        for peer in other.peers{
+            if !self.peers.iter().any(|e| e.ip == peer.ip && e.port == peer.port){
+                self.peers.push(peer);
+            }
+        }
+    }
+}
+
+// swarm health for one info hash as reported by a tracker scrape
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ScrapeStats{
+    pub seeders: u64,
+    pub leechers: u64,
+    pub downloaded: u64
+}
 
+// everything needed to announce a torrent, kept apart from the torrent so it can re-announce from its own thread
+#[derive(Debug)]
 pub struct Tracker{
-    pub torrent_mutex: Option<TorrentMutex>,
-    pub schedule_handle: Option<ScheduleHandle>,
-    pub interval: usize
+    pub announce_list: AnnounceList,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub peer_id: String,
+    pub port: u16,
+    // random but the same for every announce of the session, so trackers can recognise us when our ip changes
+    pub key: u32,
+    pub stats: Arc<TransferStats>,
+    pub interval: u64,
+    pub min_interval: Option<u64>,
+    // announce to every tracker at once instead of stopping at the first one that responds
+    pub announce_to_all: bool,
+    // the trackers that heard started from us, with the info hash they heard it for
+    started: Vec<(String, [u8; 20])>,
+    // the trackers that failed during the last announce
+    pub errors: Vec<(String, TorrentError)>,
+    // ids trackers asked us to send back with every announce
+    tracker_ids: Vec<(String, Vec<u8>)>,
+    // trackers that refused an announce and when they can be tried again, never if there is no time
+    retry_at: Vec<(String, Option<Instant>)>,
+    stop: Arc<AtomicBool>
 }
 
-fn encode_param(data: &[u8]) -> String {
+pub fn encode_param(data: &[u8]) -> String {
     let mut resp = String::new();
     for byte in data {
         let c = char::from(*byte);
@@ -43,6 +168,78 @@ fn encode_param(data: &[u8]) -> String {
     resp
 }
 
+// by convention a tracker that supports scraping has an announce url whose last path element starts with
+// "announce", the scrape url is the same with that part replaced by "scrape"
+pub fn scrape_url(announce_url: &str) -> Option<String>{
+    let (base, query) = match announce_url.find('?'){
+        Some(e) => (&announce_url[..e], &announce_url[e..]),
+        _ => (announce_url, "")
+    };
+
+    let slash = match base.rfind('/'){
+        Some(e) => e,
+        _ => return None
+    };
+
+    let last = &base[slash + 1..];
+    if !last.starts_with("announce"){
+        return None;
+    }
+
+    Some(format!("{}/scrape{}{}", &base[..slash], &last["announce".len()..], query))
+}
+
+// compact peer lists are 4 (IPv4) or 16 (IPv6) bytes of address followed by a 2 byte port for every peer
+fn compact_peers(bytes: &[u8], ipv6: bool, info_hash: [u8; 20]) -> Result<Vec<TrackerPeer>, TorrentError>{
+    let ip_len = if ipv6 { 16 } else { 4 };
+    if bytes.len() % (ip_len + 2) != 0{
+        return Err(TorrentError::new("Malformed peer length".to_string()));
+    }
+
+    Ok(bytes.chunks(ip_len + 2).map(|peer| {
+        let ip = if ipv6{
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&peer[..16]);
+            Ipv6Addr::from(octets).to_string()
+        } else {
+            Ipv4Addr::from(bytes_to_u32(&peer[..4])).to_string()
+        };
+        TrackerPeer{ip, port: bytes_to_u16(&peer[ip_len..]), peer_id: None, info_hash}
+    }).collect())
+}
+
+// the original peer list, a list of dictionaries with the ip (or host name), port and peer id of every peer
+fn dictionary_peers(list: &[Bencode], info_hash: [u8; 20]) -> Result<Vec<TrackerPeer>, TorrentError>{
+    let mut peers: Vec<TrackerPeer> = Vec::new();
+    for entry in list{
+        let dict = match entry{
+            Bencode::Dict(e) => e,
+            _ => return Err(TorrentError::new("Malformed peer list".to_string()))
+        };
+
+        let ip = match dict.get(&ByteString::from_str("ip")){
+            Some(Bencode::ByteString(e)) => String::from_utf8_lossy(e).to_string(),
+            _ => return Err(TorrentError::new("Peer in the peer list is missing its ip".to_string()))
+        };
+
+        let port: u16 = match dict.get(&ByteString::from_str("port")){
+            Some(e) => match FromBencode::from_bencode(e){
+                Ok(e) => e,
+                _ => return Err(TorrentError::new(format!("Peer {} has an invalid port", ip)))
+            },
+            _ => return Err(TorrentError::new(format!("Peer {} is missing its port", ip)))
+        };
+
+        let peer_id = match dict.get(&ByteString::from_str("peer id")){
+            Some(Bencode::ByteString(e)) if e.len() == 20 => Some(e.clone()),
+            _ => None
+        };
+
+        peers.push(TrackerPeer{ip, port, peer_id, info_hash});
+    }
+    Ok(peers)
+}
+
 fn transaction_id() -> i32{
     rand::thread_rng().gen()
 }
@@ -50,45 +247,175 @@ fn transaction_id() -> i32{
 const UDP_DEFAULT_CONNECTION_ID: i64 = 0x41727101980;
 const CONNECT: i32 = 0;
 const ANNOUNCE: i32 = 1;
-const SCRAP: i32 = 2;
+const SCRAPE: i32 = 2;
 const ERROR: i32 = 3;
+// the most info hashes that fit in one scrape packet
+const MAX_SCRAPE_HASHES: usize = 74;
+// a request is sent again after 15 * 2 ^ n seconds without a response, with n going up to 8 (BEP 15). a request
+// and its connect give up once that whole schedule has passed, counted in base timeouts: 1 + 2 + ... + 256
+const UDP_REQUEST_BUDGET: u32 = 511;
+// we are shutting down when sending stopped, so there is only time to connect and send it once
+const UDP_STOPPED_BUDGET: u32 = 2;
+#[cfg(not(test))]
+const UDP_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+#[cfg(test)]
+const UDP_BASE_TIMEOUT: Duration = Duration::from_millis(50);
+const UDP_CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+// how long to wait for the answer to the nth transmission, never past the deadline
+fn udp_timeout(attempt: u32, deadline: Instant) -> Duration{
+    UDP_BASE_TIMEOUT.saturating_mul(2u32.saturating_pow(attempt)).min(deadline.saturating_duration_since(Instant::now()))
+}
+
+// only the connection id is shared, every request has its own socket so concurrent requests never read
+// each other's responses
+struct UdpConnection{
+    url: String,
+    address: SocketAddr,
+    connection_id: i64,
+    connected_at: Instant
+}
+
+// BEP 41 options appended to a UDP announce, NOP (1) is only needed for padding which we never do
+const OPTION_END: u8 = 0;
+const OPTION_URL_DATA: u8 = 2;
+const MAX_OPTION_LEN: usize = 255;
+
+// the path and query of a UDP tracker url, split into as many URLData options as it needs
+fn url_data_options(url: &str) -> Vec<u8>{
+    let url_parsed = urlparse::urlparse(url);
+    let mut url_data = url_parsed.path.clone();
+    if let Some(query) = url_parsed.query{
+        url_data += &format!("?{}", query);
+    }
+
+    if url_data.is_empty(){
+        return Vec::new();
+    }
+
+    let mut options: Vec<u8> = Vec::new();
+    for chunk in url_data.as_bytes().chunks(MAX_OPTION_LEN){
+        options.push(OPTION_URL_DATA);
+        options.push(chunk.len() as u8);
+        options.extend(chunk);
+    }
+    options.push(OPTION_END);
+    options
+}
+
+// shared by every tracker so announces and scrapes for different torrents reuse the same connection id
+static UDP_CONNECTIONS: Mutex<Vec<UdpConnection>> = Mutex::new(Vec::new());
 
 impl Tracker{
 
-    // TODO: Implement UDP tracker protocol
+    pub fn new(torrent: &Torrent) -> Tracker{
+        Tracker{
+            announce_list: torrent.info.announce_list.clone(),
+            info_hashes: torrent.info.info_hashes(),
+            peer_id: torrent.id.clone(),
+            port: torrent.port,
+            key: torrent.key,
+            stats: torrent.stats.clone(),
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            announce_to_all: torrent.announce_to_all,
+            started: Vec::new(),
+            errors: Vec::new(),
+            tracker_ids: Vec::new(),
+            retry_at: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false))
+        }
+    }
 
-    fn udp_write(socket: &mut UdpSocket, msg: Vec<u8>) -> Result<(), TorrentError>{
-        for i in 1..3{
-            socket.set_write_timeout(Some(Duration::from_secs(2u64.pow(i) * 15))).unwrap();
-            let size_sent = match socket.send(&msg){
-                Ok(e) => e,
-                Err(e) => {println!("{:#?}", e);continue}
-            };
+    // an error response is the action followed by the transaction id and a message
+    fn udp_error(response: &[u8], transaction_id: i32) -> Option<TorrentError>{
+        if response.len() < 8 || bytes_to_i32(&response[0..4]) != ERROR || bytes_to_i32(&response[4..8]) != transaction_id{
+            return None;
+        }
+        Some(TorrentError::new(format!("UDP tracker responded with an error: {}", String::from_utf8_lossy(&response[8..]))))
+    }
 
-            if size_sent != msg.len(){
-                return Err(TorrentError::new("Unable to write entire message to the UDP client".to_string()));
-            }
-            else{
-                return Ok(())
+    // wait for the response to a request, anything with another transaction id is a late answer to an earlier one
+    fn udp_receive(socket: &UdpSocket, transaction_id: i32, timeout: Duration, response: &mut [u8]) -> Result<Option<usize>, TorrentError>{
+        let deadline = Instant::now() + timeout;
+        loop{
+            let now = Instant::now();
+            if now >= deadline{
+                return Ok(None);
             }
+
+            socket.set_read_timeout(Some(deadline - now)).unwrap_or(());
+            match socket.recv(response){
+                Ok(size) if size >= 8 && bytes_to_i32(&response[4..8]) == transaction_id => return Ok(Some(size)),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(TorrentError::new(e.to_string()))
+            };
         }
+    }
 
-        return Err(TorrentError::new("Writing to UDP client timed out".to_string()))
+    fn udp_send(socket: &UdpSocket, msg: &[u8]) -> Result<(), TorrentError>{
+        match socket.send(msg){
+            Ok(e) if e == msg.len() => Ok(()),
+            Ok(_) => Err(TorrentError::new("Unable to write entire message to the UDP client".to_string())),
+            Err(e) => Err(TorrentError::new(e.to_string()))
+        }
     }
 
-    fn announce_udp(torrent_info: &mut Torrent) -> Result<Vec<Box<Peer>>, TorrentError>{
-        // create a UDP socket with the tracker
-        let mut socket = match UdpSocket::bind("0.0.0.0:0"){
+    fn udp_socket(address: SocketAddr) -> Result<UdpSocket, TorrentError>{
+        let socket = match UdpSocket::bind(if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }){
             Ok(e) => e,
             Err(e) => return Err(TorrentError::new(e.to_string()))
         };
 
+        match socket.connect(address){
+            Ok(_) => Ok(socket),
+            Err(e) => Err(TorrentError::new(e.to_string()))
+        }
+    }
 
-        let url_parsed = urlparse::urlparse(torrent_info.info.announce_url.clone());
-        let udp_ip = lookup_host(&url_parsed.hostname.unwrap()).unwrap()[0];
+    // open a socket to a UDP tracker and get the connection id every other request has to include, a tracker
+    // with both IPv6 and IPv4 addresses is tried over IPv6 first
+    fn udp_connect(url: &str, deadline: Instant) -> Result<(UdpSocket, i64), TorrentError>{
+        let url_parsed = urlparse::urlparse(url);
+        let udp_port = match url_parsed.port{
+            Some(e) => e,
+            _ => return Err(TorrentError::new(format!("Tracker {} is missing a port", url)))
+        };
 
-        socket.connect((udp_ip, url_parsed.port.unwrap())).expect("Could not connect");
-        socket.set_read_timeout(Some(Duration::from_secs(15)));
+        // IPv6 literals keep their brackets in the url
+        let hostname = url_parsed.hostname.unwrap_or_default();
+        let mut addresses: Vec<IpAddr> = match lookup_host(hostname.trim_start_matches('[').trim_end_matches(']')){
+            Ok(e) if !e.is_empty() => e,
+            _ => return Err(TorrentError::new(format!("Unable to resolve the tracker {}", url)))
+        };
+        addresses.sort_by_key(|e| !e.is_ipv6());
+        addresses.dedup();
+
+        let addresses: Vec<SocketAddr> = addresses.into_iter().map(|e| SocketAddr::new(e, udp_port)).collect();
+        Tracker::udp_connect_addresses(&addresses, url, deadline)
+    }
+
+    // every address but the last only gets a single transmission, so a host with broken IPv6 routing falls
+    // back to IPv4 after one base timeout instead of spending the whole budget on it
+    fn udp_connect_addresses(addresses: &[SocketAddr], url: &str, deadline: Instant) -> Result<(UdpSocket, i64), TorrentError>{
+        let mut last_error = TorrentError::new(format!("Unable to resolve the tracker {}", url));
+        for (i, address) in addresses.iter().enumerate(){
+            let address_deadline = if i + 1 == addresses.len() { deadline } else { deadline.min(Instant::now() + UDP_BASE_TIMEOUT) };
+            match Tracker::udp_handshake(*address, url, address_deadline){
+                Ok(e) => return Ok(e),
+                Err(e) => last_error = e
+            };
+        }
+
+        Err(last_error)
+    }
+
+    fn udp_handshake(address: SocketAddr, url: &str, deadline: Instant) -> Result<(UdpSocket, i64), TorrentError>{
+        let socket = match Tracker::udp_socket(address){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
         // build our connection method with the following form
         // Bytes 0-8: Connection ID, use default for initial connection request
         // Bytes 8-12: Action type
@@ -100,77 +427,170 @@ impl Tracker{
         connect_msg.extend(i32_to_bytes(CONNECT));
         connect_msg.extend(i32_to_bytes(connect_transaction_id));
 
-        // send our connect request
-        match Tracker::udp_write(&mut socket, connect_msg){
-            Ok(_) => (),
-            Err(e) => return Err(e)
-        };
+        let mut connect_resp: [u8; 1024] = [0; 1024];
+        let mut connect_resp_size = None;
+        let mut n = 0;
+        while connect_resp_size.is_none() && Instant::now() < deadline{
+            match Tracker::udp_send(&socket, &connect_msg){
+                Ok(_) => (),
+                Err(e) => return Err(e)
+            };
 
-        let mut connect_resp: [u8; 16] = [0; 16];
-        let connect_resp_size = match socket.recv(&mut connect_resp){
-            Ok(e) => e,
-            Err(e) => return Err(TorrentError::new(e.to_string()))
+            connect_resp_size = match Tracker::udp_receive(&socket, connect_transaction_id, udp_timeout(n, deadline), &mut connect_resp){
+                Ok(e) => e,
+                Err(e) => return Err(e)
+            };
+            n += 1;
+        }
+
+        let connect_resp_size = match connect_resp_size{
+            Some(e) => e,
+            _ => return Err(TorrentError::new(format!("UDP tracker {} did not respond", url)))
         };
 
-        if connect_resp_size != 16{
-            return Err(TorrentError::new("Received connect response that is too long".to_string()));
+        if let Some(e) = Tracker::udp_error(&connect_resp[..connect_resp_size], connect_transaction_id){
+            return Err(e);
         }
 
-        // verify the transaction ID and action returned are what we expect
-        if bytes_to_i32(&connect_resp[4..8]) != connect_transaction_id{
-            return Err(TorrentError::new("UDP response with incorrect transaction id".to_string()));
+        if connect_resp_size != 16{
+            return Err(TorrentError::new("Received connect response with an invalid size".to_string()));
         }
 
-        if bytes_to_i32(&connect_resp[0..4]) != 0{
+        if bytes_to_i32(&connect_resp[0..4]) != CONNECT{
             return Err(TorrentError::new("Incorrect action from UDP response".to_string()));
         }
 
-        // set our connection id returned by the server for the rest of the connection with the tracker
-        let connection_id = bytes_to_i64(&connect_resp[8..]);
+        // the connection id returned by the server is used for the rest of the connection with the tracker
+        Ok((socket, bytes_to_i64(&connect_resp[8..16])))
+    }
+
+    // a connection id can be used for a minute after it was handed out, by any number of announces and scrapes.
+    // `socket` is the request's socket, it is replaced when a new connection is made or the address changed
+    fn udp_connection(url: &str, socket: &mut Option<UdpSocket>, deadline: Instant) -> Result<i64, TorrentError>{
+        let mut connections = UDP_CONNECTIONS.lock().unwrap();
+        connections.retain(|e| e.connected_at.elapsed() < UDP_CONNECTION_LIFETIME);
+
+        if let Some(connection) = connections.iter().find(|e| e.url == url){
+            let (address, connection_id) = (connection.address, connection.connection_id);
+            drop(connections);
+
+            if socket.as_ref().and_then(|e| e.peer_addr().ok()) != Some(address){
+                *socket = match Tracker::udp_socket(address){
+                    Ok(e) => Some(e),
+                    Err(e) => return Err(e)
+                };
+            }
+            return Ok(connection_id);
+        }
+        // don't hold up other trackers while this one connects
+        drop(connections);
+
+        let (connected, connection_id) = match Tracker::udp_connect(url, deadline){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+
+        if let Ok(address) = connected.peer_addr(){
+            UDP_CONNECTIONS.lock().unwrap().push(UdpConnection{url: url.to_string(), address, connection_id, connected_at: Instant::now()});
+        }
+        *socket = Some(connected);
+        Ok(connection_id)
+    }
+
+    fn udp_forget(url: &str){
+        UDP_CONNECTIONS.lock().unwrap().retain(|e| e.url != url);
+    }
+
+    // send a request built around the connection id and retransmit it until it is answered or `budget` base
+    // timeouts have passed, a fresh connection id is fetched whenever the last one expired in the meantime
+    fn udp_request(url: &str, transaction_id: i32, build: &dyn Fn(i64) -> Vec<u8>, response: &mut [u8], budget: u32) -> Result<(usize, SocketAddr), TorrentError>{
+        let deadline = Instant::now() + UDP_BASE_TIMEOUT * budget;
+        // one socket for the whole request so a late answer to an earlier transmission still arrives
+        let mut socket: Option<UdpSocket> = None;
+        let mut n = 0;
+
+        while Instant::now() < deadline{
+            let connection_id = match Tracker::udp_connection(url, &mut socket, deadline){
+                Ok(e) => e,
+                Err(e) => return Err(e)
+            };
+            let current = match &socket{
+                Some(e) => e,
+                _ => return Err(TorrentError::new(format!("UDP tracker {} has no socket", url)))
+            };
 
-        let mut announce_msg: Vec<u8> = Vec::new();
+            let received = match Tracker::udp_send(current, &build(connection_id)){
+                Ok(_) => Tracker::udp_receive(current, transaction_id, udp_timeout(n, deadline), response),
+                Err(e) => Err(e)
+            };
+            n += 1;
+
+            match received{
+                Ok(Some(size)) => {
+                    // the tracker may have dropped our connection id early
+                    if Tracker::udp_error(&response[..size], transaction_id).is_some(){
+                        Tracker::udp_forget(url);
+                    }
+                    return match current.peer_addr(){
+                        Ok(address) => Ok((size, address)),
+                        Err(e) => Err(TorrentError::new(e.to_string()))
+                    };
+                },
+                Ok(None) => (),
+                Err(e) => {
+                    Tracker::udp_forget(url);
+                    return Err(e);
+                }
+            };
+        }
+
+        Tracker::udp_forget(url);
+        Err(TorrentError::new(format!("UDP tracker {} did not respond", url)))
+    }
+
+    fn announce_udp(&self, url: &str, info_hash: [u8; 20], event: AnnounceEvent) -> Result<AnnounceResponse, TorrentError>{
         let announce_transaction_id = transaction_id();
-        announce_msg.extend(i64_to_bytes(connection_id));
-        // action = 1 for announce
-        announce_msg.extend(i32_to_bytes(1));
-        announce_msg.extend(i32_to_bytes(announce_transaction_id));
-        announce_msg.extend(&torrent_info.info.info_hash);
-        announce_msg.extend(torrent_info.id.clone().into_bytes());
-        // 0 bytes downloaded
-        announce_msg.extend(i64_to_bytes(0));
-        // all the bytes left
-        announce_msg.extend(i64_to_bytes(torrent_info.info.byte_size as i64));
-        // 0 bytes uploaded
-        announce_msg.extend(i64_to_bytes(0));
-        // Event type is 2 = Download
-        announce_msg.extend(i32_to_bytes(2));
-        // IP Address = 0
-        announce_msg.extend(u32_to_bytes(0));
-        // Random key
-        announce_msg.extend(u32_to_bytes(rand::thread_rng().gen()));
-        // Number of peers requested = -1 for all
-        announce_msg.extend(i32_to_bytes(200));
-        // Port, doesn't really matter because we can't seed yet but ok
-        // speaking of which, TODO: implement seeding functionality
-        announce_msg.extend(u16_to_bytes(6800));
-        // extensions are all 0
-        announce_msg.extend(u16_to_bytes(0));
-
-        Tracker::udp_write(&mut socket, announce_msg).expect("Unable to write announce request");
+        let options = url_data_options(url);
+        let build = |connection_id: i64| -> Vec<u8>{
+            let mut announce_msg: Vec<u8> = Vec::new();
+            announce_msg.extend(i64_to_bytes(connection_id));
+            announce_msg.extend(i32_to_bytes(ANNOUNCE));
+            announce_msg.extend(i32_to_bytes(announce_transaction_id));
+            announce_msg.extend(&info_hash);
+            announce_msg.extend(self.peer_id.clone().into_bytes());
+            announce_msg.extend(i64_to_bytes(self.stats.downloaded.load(Ordering::Relaxed) as i64));
+            announce_msg.extend(i64_to_bytes(self.stats.left.load(Ordering::Relaxed) as i64));
+            announce_msg.extend(i64_to_bytes(self.stats.uploaded.load(Ordering::Relaxed) as i64));
+            announce_msg.extend(i32_to_bytes(event.udp_id()));
+            // IP Address = 0
+            announce_msg.extend(u32_to_bytes(0));
+            announce_msg.extend(u32_to_bytes(self.key));
+            // Number of peers requested = -1 for all
+            announce_msg.extend(i32_to_bytes(200));
+            // TODO: implement seeding functionality, until then nothing listens on this port
+            announce_msg.extend(u16_to_bytes(self.port));
+            // private trackers keep passkeys in the path or query of the url
+            announce_msg.extend(&options);
+            announce_msg
+        };
 
         // read the first 20 bytes to get info on the number of peers we are receiving
-        let mut announce_info = [0u8; 20 + 2000 * 6];
-        let announce_resp_size = match socket.recv(&mut announce_info){
+        let mut announce_info = [0u8; 20 + 2000 * 18];
+        let budget = if event == AnnounceEvent::Stopped { UDP_STOPPED_BUDGET } else { UDP_REQUEST_BUDGET };
+        let (announce_resp_size, address) = match Tracker::udp_request(url, announce_transaction_id, &build, &mut announce_info, budget){
             Ok(e) => e,
-            Err(e) => return Err(TorrentError::new(e.to_string()))
+            Err(e) => return Err(e)
         };
 
-        // if there is less than one peer panic
-        if announce_resp_size < 26 || (announce_resp_size - 20) % 6 != 0{
+        if let Some(e) = Tracker::udp_error(&announce_info[..announce_resp_size], announce_transaction_id){
+            return Err(e);
+        }
+
+        if announce_resp_size < 20{
             return Err(TorrentError::new("UDP tracker returned invalid announce DGRAM header size".to_string()))
         }
 
-        if bytes_to_i32(&announce_info[0..4]) != 1{
+        if bytes_to_i32(&announce_info[0..4]) != ANNOUNCE{
             return Err(TorrentError::new("Invalid action, expected announce".to_string()));
         }
 
@@ -178,55 +598,55 @@ impl Tracker{
             return Err(TorrentError::new("Invalid transaction ID returned after announce".to_string()));
         }
 
-        // for now ignore the interval because we can't really handle re-announce currently :(
-        let num_seeders = bytes_to_i32(&announce_info[16..20]);
-        let mut peers: Vec<Box<Peer>> = Vec::new();
-
-        for i in 0..((announce_resp_size-20)/6){
-            let start = 20 + (i * 6);
-            let end = 20 + ((i+1) * 6);
-            let ip_addr = bytes_to_u32(&announce_info[start..end-2]);
-            let port = bytes_to_u16(&announce_info[start+4..end]);
-
-            let ip = Ipv4Addr::from(ip_addr).to_string();
-
-            peers.push(Box::new(Peer::new(
-                ip,
-                port,
-                None,
-                torrent_info.id.clone(),
-                torrent_info.info.info_hash,
-                torrent_info.info.num_pieces,
-                torrent_info.info.piece_byte_size as usize
-            )))
-        }
+        let interval = bytes_to_i32(&announce_info[8..12]).max(0) as u64;
+        // a tracker reached over IPv6 sends 18 byte IPv6 peers instead of 6 byte IPv4 ones (BEP 15)
+        let peers = match compact_peers(&announce_info[20..announce_resp_size], address.is_ipv6(), info_hash){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
 
-        Ok(peers)
+        Ok(AnnounceResponse{
+            peers,
+            interval,
+            leechers: Some(bytes_to_u32(&announce_info[12..16]) as u64),
+            seeders: Some(bytes_to_u32(&announce_info[16..20]) as u64),
+            ..AnnounceResponse::empty()
+        })
     }
 
 
     // TODO: Implement DHL tracker protocol
 
-    fn announce_http(torrent_info: &mut Torrent) -> Result<Vec<Box<Peer>>, TorrentError>{
-        let params = [("info_hash", encode_param(&torrent_info.info.info_hash)),
-            ("peer_id", encode_param(&torrent_info.id.as_bytes())),
-            ("port", torrent_info.port.to_string()), ("uploaded", torrent_info.uploaded.to_string()),
-            ("downloaded", torrent_info.downloaded.to_string()), ("compact", String::from("1")),
-            ("left", (torrent_info.info.byte_size - torrent_info.downloaded as u64).to_string()),
-            ("event", "started".to_string()), ("numwant", "100".to_string())];
+    fn announce_http(&self, url: &str, info_hash: [u8; 20], event: AnnounceEvent) -> Result<AnnounceResponse, TorrentError>{
+        let params = [("info_hash", encode_param(&info_hash)),
+            ("peer_id", encode_param(&self.peer_id.as_bytes())),
+            ("port", self.port.to_string()), ("uploaded", self.stats.uploaded.load(Ordering::Relaxed).to_string()),
+            ("downloaded", self.stats.downloaded.load(Ordering::Relaxed).to_string()), ("compact", String::from("1")),
+            ("left", self.stats.left.load(Ordering::Relaxed).to_string()),
+            ("key", format!("{:08x}", self.key)), ("numwant", "100".to_string())];
 
         let mut param_vec = Vec::new();
         for (key, val) in params.iter(){
             param_vec.push(format!("{}={}", key, val));
         }
+        if let Some(name) = event.http_name(){
+            param_vec.push(format!("event={}", name));
+        }
+        if let Some((_, tracker_id)) = self.tracker_ids.iter().find(|(e, _)| e == url){
+            param_vec.push(format!("trackerid={}", encode_param(tracker_id)));
+        }
 
-        let url = format!("{}?{}", torrent_info.info.announce_url, param_vec.join("&"));
+        let request_url = format!("{}?{}", url, param_vec.join("&"));
 
-        let response = match minreq::get(url).send() {
+        let response = match minreq::get(request_url).send() {
             Ok(e) => e.into_bytes(),
             _ => return Err(TorrentError::new("Unable to get a response from the tracker".to_string()))
         };
 
+        // the bencode crate recurses without a limit, so check the nesting before handing it over
+        if bencode_raw::value_len(&response).is_none(){
+            return Err(TorrentError::new(String::from("Tracker sent invalid bencode")));
+        }
 
         let bencodeing= match bencode::from_vec(response){
             Ok(e) => e,
@@ -238,70 +658,1008 @@ impl Tracker{
             _ => return Err(TorrentError::new("Invalid bencode".to_string()))
         };
 
-        let _interval: usize = match peer_dict.get(&ByteString::from_str("interval")){
-            Some(e) => {match FromBencode::from_bencode(e){Ok(b)=>b, _=>1000*1000}},
-            _ => 1000 * 1000
+        let text = |key: &str| -> Option<String>{
+            match peer_dict.get(&ByteString::from_str(key)){
+                Some(Bencode::ByteString(e)) => Some(String::from_utf8_lossy(e).to_string()),
+                _ => None
+            }
+        };
+        let number = |key: &str| -> Option<u64>{
+            match peer_dict.get(&ByteString::from_str(key)){
+                Some(e) => FromBencode::from_bencode(e).ok(),
+                _ => None
+            }
         };
 
-        let byte_peers: &Vec<u8> = match peer_dict.get(&ByteString::from_str("peers")){
-            Some(e) => {match e{Bencode::ByteString(b) => b, _ => return Err(TorrentError::new("Malformed peer list".to_string()))}},
-            _ => return Err(TorrentError::new("Could not find peer list".to_string()))
+        if let Some(reason) = text("failure reason"){
+            let retry_in = match text("retry in"){
+                Some(e) if e == "never" => Some(RetryIn::Never),
+                _ => number("retry in").map(RetryIn::Minutes)
+            };
+            return Ok(AnnounceResponse{failure_reason: Some(reason), retry_in, ..AnnounceResponse::empty()});
+        }
+
+        let interval: u64 = match peer_dict.get(&ByteString::from_str("interval")){
+            Some(e) => {match FromBencode::from_bencode(e){Ok(b)=>b, _=>DEFAULT_INTERVAL}},
+            _ => DEFAULT_INTERVAL
         };
 
-        let mut peers: Vec<Box<Peer>> = Vec::new();
+        let min_interval: Option<u64> = match peer_dict.get(&ByteString::from_str("min interval")){
+            Some(e) => FromBencode::from_bencode(e).ok(),
+            _ => None
+        };
 
-        if byte_peers.len() % 6 != 0{
-            return Err(TorrentError::new("Malformed peer length".to_string()))
+        if !peer_dict.contains_key(&ByteString::from_str("peers")) && !peer_dict.contains_key(&ByteString::from_str("peers6")){
+            return Err(TorrentError::new("Could not find peer list".to_string()));
         }
 
-        for i in 0..byte_peers.len() / 6{
-            let peer_slice = &byte_peers[i * 6 .. (i+1)*6];
-            let ip: String = format!("{}.{}.{}.{}", peer_slice[0].to_string(), peer_slice[1].to_string(), peer_slice[2].to_string(), peer_slice[3].to_string());
-            let port: u16 = ((peer_slice[4] as u16) << 8)+ peer_slice[5] as u16;
-            peers.push(Box::new(Peer::new(
-                ip,
-                port,
-                None,
-                torrent_info.id.clone(),
-                torrent_info.info.info_hash,
-                torrent_info.info.num_pieces,
-                torrent_info.info.piece_byte_size as usize
-            )));
+        // IPv4 peers are in peers and IPv6 peers in peers6 (BEP 7), peers can also be a list of dictionaries
+        let mut peers: Vec<TrackerPeer> = Vec::new();
+        for (key, ipv6) in [("peers", false), ("peers6", true)].iter(){
+            let found = match peer_dict.get(&ByteString::from_str(key)){
+                Some(Bencode::ByteString(b)) => compact_peers(b, *ipv6, info_hash),
+                Some(Bencode::List(list)) if !*ipv6 => dictionary_peers(list, info_hash),
+                Some(_) => Err(TorrentError::new("Malformed peer list".to_string())),
+                None => Ok(Vec::new())
+            };
+
+            match found{
+                Ok(e) => peers.extend(e),
+                Err(e) => return Err(e)
+            };
+        }
+
+        Ok(AnnounceResponse{
+            peers,
+            interval,
+            min_interval,
+            tracker_id: match peer_dict.get(&ByteString::from_str("tracker id")){
+                Some(Bencode::ByteString(e)) => Some(e.clone()),
+                _ => None
+            },
+            seeders: number("complete"),
+            leechers: number("incomplete"),
+            warnings: text("warning message").map(|e| (url.to_string(), e)).into_iter().collect(),
+            ..AnnounceResponse::empty()
+        })
+    }
+
+    fn scrape_http(url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<([u8; 20], ScrapeStats)>, TorrentError>{
+        let scrape = match scrape_url(url){
+            Some(e) => e,
+            _ => return Err(TorrentError::new(format!("Tracker {} does not support scraping", url)))
+        };
+
+        let params: Vec<String> = info_hashes.iter().map(|e| format!("info_hash={}", encode_param(e))).collect();
+        let separator = if scrape.contains('?') { "&" } else { "?" };
+        let response = match minreq::get(format!("{}{}{}", scrape, separator, params.join("&"))).send(){
+            Ok(e) => e.into_bytes(),
+            _ => return Err(TorrentError::new(format!("Unable to get a response from the tracker {}", url)))
+        };
+
+        if bencode_raw::value_len(&response).is_none(){
+            return Err(TorrentError::new("Invalid bencode".to_string()));
+        }
+
+        let dict: BTreeMap<ByteString, Bencode> = match bencode::from_vec(response){
+            Ok(Bencode::Dict(e)) => e,
+            Ok(_) => return Err(TorrentError::new("Invalid bencode".to_string())),
+            Err(e) => return Err(TorrentError::new(e.msg))
+        };
+
+        if let Some(Bencode::ByteString(reason)) = dict.get(&ByteString::from_str("failure reason")){
+            return Err(TorrentError::new(format!("Tracker {} refused the scrape: {}", url, String::from_utf8_lossy(reason))));
+        }
+
+        let files = match dict.get(&ByteString::from_str("files")){
+            Some(Bencode::Dict(e)) => e,
+            _ => return Err(TorrentError::new("Could not find files in the scrape response".to_string()))
+        };
+
+        let number = |stats: &BTreeMap<ByteString, Bencode>, key: &str| -> u64{
+            match stats.get(&ByteString::from_str(key)){
+                Some(e) => FromBencode::from_bencode(e).unwrap_or(0),
+                _ => 0
+            }
+        };
+
+        // trackers leave out the hashes they don't know about
+        let mut ret: Vec<([u8; 20], ScrapeStats)> = Vec::new();
+        for info_hash in info_hashes{
+            if let Some(Bencode::Dict(stats)) = files.get(&ByteString::from_slice(info_hash)){
+                ret.push((*info_hash, ScrapeStats{
+                    seeders: number(stats, "complete"),
+                    leechers: number(stats, "incomplete"),
+                    downloaded: number(stats, "downloaded")
+                }));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn scrape_udp(url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<([u8; 20], ScrapeStats)>, TorrentError>{
+        let mut ret: Vec<([u8; 20], ScrapeStats)> = Vec::new();
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES){
+            let scrape_transaction_id = transaction_id();
+            let build = |connection_id: i64| -> Vec<u8>{
+                let mut scrape_msg: Vec<u8> = Vec::new();
+                scrape_msg.extend(i64_to_bytes(connection_id));
+                scrape_msg.extend(i32_to_bytes(SCRAPE));
+                scrape_msg.extend(i32_to_bytes(scrape_transaction_id));
+                for info_hash in chunk{
+                    scrape_msg.extend(info_hash);
+                }
+                scrape_msg
+            };
+
+            let mut scrape_resp = [0u8; 8 + MAX_SCRAPE_HASHES * 12];
+            let scrape_resp_size = match Tracker::udp_request(url, scrape_transaction_id, &build, &mut scrape_resp, UDP_REQUEST_BUDGET){
+                Ok((e, _)) => e,
+                Err(e) => return Err(e)
+            };
+
+            if let Some(e) = Tracker::udp_error(&scrape_resp[..scrape_resp_size], scrape_transaction_id){
+                return Err(e);
+            }
+
+            // Bytes 0-4: action, Bytes 4-8: transaction id, then seeders, completed and leechers for every hash in order
+            if scrape_resp_size != 8 + chunk.len() * 12{
+                return Err(TorrentError::new("UDP tracker returned an invalid scrape response size".to_string()));
+            }
+
+            if bytes_to_i32(&scrape_resp[0..4]) != SCRAPE{
+                return Err(TorrentError::new("Invalid action, expected scrape".to_string()));
+            }
+
+            if bytes_to_i32(&scrape_resp[4..8]) != scrape_transaction_id{
+                return Err(TorrentError::new("Invalid transaction ID returned after scrape".to_string()));
+            }
+
+            for (i, info_hash) in chunk.iter().enumerate(){
+                let stats = &scrape_resp[8 + i * 12 .. 8 + (i + 1) * 12];
+                ret.push((*info_hash, ScrapeStats{
+                    seeders: bytes_to_u32(&stats[0..4]) as u64,
+                    downloaded: bytes_to_u32(&stats[4..8]) as u64,
+                    leechers: bytes_to_u32(&stats[8..12]) as u64
+                }));
+            }
         }
 
-        Ok(peers)
+        Ok(ret)
     }
 
-    pub fn announce(torrent_info: &mut Torrent) -> Result<Vec<Box<Peer>>, TorrentError>{
+    // ask a single tracker about several torrents at once without announcing
+    pub fn scrape_tracker(url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<([u8; 20], ScrapeStats)>, TorrentError>{
+        if url.starts_with("http"){
+            return Tracker::scrape_http(url, info_hashes);
+        }
+        else if url.starts_with("udp"){
+            return Tracker::scrape_udp(url, info_hashes);
+        }
+        Err(TorrentError::new(format!("Scraping is not supported for tracker {}", url)))
+    }
 
-        if torrent_info.info.announce_url.starts_with("http"){
-            return Tracker::announce_http(torrent_info);
+    fn announce_url(&self, url: &str, info_hash: [u8; 20], event: AnnounceEvent) -> Result<AnnounceResponse, TorrentError>{
+
+        if url.starts_with("http"){
+            return self.announce_http(url, info_hash, event);
         }
-        else if torrent_info.info.announce_url.starts_with("udp"){
-            return Tracker::announce_udp(torrent_info);
+        else if url.starts_with("udp"){
+            return self.announce_udp(url, info_hash, event);
         }
         else{
             return Err(TorrentError::new("Unsupported tracker protocol".to_string()));
         }
     }
+
+    fn can_announce(&self, url: &str) -> bool{
+        match self.retry_at.iter().find(|(e, _)| e == url){
+            Some((_, Some(retry_at))) => Instant::now() >= *retry_at,
+            Some((_, None)) => false,
+            _ => true
+        }
+    }
+
+    // keep what a tracker asked us to remember, a refusal is turned into an error
+    fn accept(&mut self, url: &str, response: AnnounceResponse) -> Result<AnnounceResponse, TorrentError>{
+        self.retry_at.retain(|(e, _)| e != url);
+        if let Some(reason) = response.failure_reason{
+            match response.retry_in{
+                // a wait too long to represent is as good as never
+                Some(RetryIn::Minutes(e)) => self.retry_at.push((url.to_string(), Instant::now().checked_add(Duration::from_secs(e.saturating_mul(60))))),
+                Some(RetryIn::Never) => self.retry_at.push((url.to_string(), None)),
+                None => ()
+            };
+            return Err(TorrentError::new(format!("Tracker refused the announce: {}", reason)));
+        }
+
+        if let Some(tracker_id) = &response.tracker_id{
+            self.tracker_ids.retain(|(e, _)| e != url);
+            self.tracker_ids.push((url.to_string(), tracker_id.clone()));
+        }
+        Ok(response)
+    }
+
+    // what a tracker should hear: the first announce it gets has to be started, even if it is a regular one,
+    // and a tracker that never heard started has nothing to complete or stop
+    fn event_for(&self, url: &str, info_hash: [u8; 20], event: AnnounceEvent) -> Option<AnnounceEvent>{
+        let started = self.started.iter().any(|(e, hash)| e == url && *hash == info_hash);
+        match event{
+            AnnounceEvent::None if !started => Some(AnnounceEvent::Started),
+            AnnounceEvent::Completed | AnnounceEvent::Stopped if !started => None,
+            e => Some(e)
+        }
+    }
+
+    fn record_event(&mut self, url: &str, info_hash: [u8; 20], event: AnnounceEvent){
+        match event{
+            AnnounceEvent::Started => self.started.push((url.to_string(), info_hash)),
+            AnnounceEvent::Stopped => self.started.retain(|(e, hash)| e != url || *hash != info_hash),
+            _ => ()
+        };
+    }
+
+    // try the trackers tier by tier until one responds, which is then promoted within its tier (BEP 12)
+    fn announce_hash(&mut self, info_hash: [u8; 20], event: AnnounceEvent) -> Option<AnnounceResponse>{
+        for url in self.announce_list.urls(){
+            if !self.can_announce(&url){
+                continue;
+            }
+
+            let event = match self.event_for(&url, info_hash, event){
+                Some(e) => e,
+                _ => continue
+            };
+
+            match self.announce_url(&url, info_hash, event).and_then(|e| self.accept(&url, e)){
+                Ok(e) => {
+                    self.record_event(&url, info_hash, event);
+                    self.announce_list.promote(&url);
+                    return Some(e);
+                },
+                Err(e) => self.errors.push((url, e))
+            };
+        }
+
+        None
+    }
+
+    // contact every tracker of every tier at the same time and merge whatever they send back
+    fn announce_hash_all(&mut self, info_hash: [u8; 20], event: AnnounceEvent) -> Option<AnnounceResponse>{
+        let urls: Vec<(String, AnnounceEvent)> = self.announce_list.urls().into_iter()
+            .filter(|e| self.can_announce(e))
+            .filter_map(|e| self.event_for(&e, info_hash, event).map(|event| (e, event)))
+            .collect();
+        let tracker = &*self;
+        let results: Vec<(String, AnnounceEvent, Result<AnnounceResponse, TorrentError>)> = thread::scope(|scope|{
+            let handles: Vec<_> = urls.iter().map(|(url, event)| scope.spawn(move || tracker.announce_url(url, info_hash, *event))).collect();
+            urls.iter().cloned().zip(handles.into_iter()).map(|((url, event), handle)| match handle.join(){
+                Ok(e) => (url, event, e),
+                Err(_) => (url, event, Err(TorrentError::new(String::from("Tracker thread panicked"))))
+            }).collect()
+        });
+
+        let mut ret: Option<AnnounceResponse> = None;
+        for (url, event, result) in results{
+            match result.and_then(|e| self.accept(&url, e)){
+                Ok(e) => {
+                    self.record_event(&url, info_hash, event);
+                    ret.get_or_insert_with(AnnounceResponse::empty).merge(e)
+                },
+                Err(e) => self.errors.push((url, e))
+            };
+        }
+
+        ret
+    }
+
+    // hybrid torrents are announced under both their v1 and truncated v2 info hashes, a tracker that
+    // fails is recorded in errors and only an error is returned when none of them responded
+    pub fn announce(&mut self, event: AnnounceEvent) -> Result<AnnounceResponse, TorrentError>{
+        let mut ret: Option<AnnounceResponse> = None;
+        self.errors.clear();
+
+        for info_hash in self.info_hashes.clone(){
+            let response = if self.announce_to_all{
+                self.announce_hash_all(info_hash, event)
+            } else {
+                self.announce_hash(info_hash, event)
+            };
+
+            if let Some(response) = response{
+                ret.get_or_insert_with(AnnounceResponse::empty).merge(response);
+            }
+        }
+
+        match ret{
+            Some(e) => {
+                self.interval = e.interval.max(e.min_interval.unwrap_or(0)).max(MIN_INTERVAL);
+                self.min_interval = e.min_interval;
+                Ok(e)
+            },
+            _ => {
+                self.interval = RETRY_INTERVAL;
+                if self.announce_list.is_empty(){
+                    return Err(TorrentError::new(String::from("No trackers to announce to")));
+                }
+                Err(TorrentError::new(format!("None of the {} trackers responded", self.announce_list.urls().len())))
+            }
+        }
+    }
+
+    // the events we leave the swarm with, completed only when the download that was missing something when the
+    // re-announce thread started has nothing left now
+    fn finish(&mut self, had_left: bool){
+        let events = if had_left && self.stats.left.load(Ordering::Relaxed) == 0 { vec![AnnounceEvent::Completed, AnnounceEvent::Stopped] } else { vec![AnnounceEvent::Stopped] };
+        for event in events{
+            if let Err(e) = self.announce(event){
+                thread_println!("{}", e.details);
+            }
+        }
+    }
+
+    // announce again every interval until stopped, sending every response back to the download, and
+    // announce the final events before the thread ends
+    fn start_reannounce(mut tracker: Tracker, response_sender: Sender<AnnounceResponse>) -> JoinHandle<()>{
+        let had_left = tracker.stats.left.load(Ordering::Relaxed) > 0;
+        thread::spawn(move ||{
+            'announcing: loop{
+                // sleep in small steps so stopping doesn't have to wait out the whole interval
+                for _ in 0..tracker.interval{
+                    if tracker.stop.load(Ordering::Relaxed){
+                        break 'announcing;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+
+                let result = tracker.announce(AnnounceEvent::None);
+                for (url, e) in tracker.errors.iter(){
+                    thread_println!("{} ({})", e.details, url);
+                }
+
+                match result{
+                    Ok(e) => {
+                        thread_println!("[{}] Re-announced to tracker - received {} peers, next announce in {} seconds", "*".green(), e.peers.len(), tracker.interval);
+                        if response_sender.send(e).is_err(){
+                            break 'announcing;
+                        }
+                    },
+                    Err(e) => thread_println!("{}", e.details)
+                };
+            }
+            tracker.finish(had_left);
+        })
+    }
+}
+
+// owns the tracker for the whole download, first directly and then through the re-announce thread. dropping it
+// sends the final events, so the trackers hear stopped however the download ends, including early returns and panics
+pub struct Announcer{
+    tracker: Option<Tracker>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
 }
 
-impl Debug for Tracker{
-    fn fmt(&self, _f: &mut Formatter<'_>) -> Result<(),std::fmt::Error> {
-        unimplemented!()
+impl Announcer{
+    pub fn new(tracker: Tracker) -> Announcer{
+        Announcer{stop: tracker.stop.clone(), tracker: Some(tracker), handle: None}
+    }
+
+    // the tracker, until the re-announce thread takes it over
+    pub fn tracker(&mut self) -> &mut Tracker{
+        self.tracker.as_mut().expect("The tracker was handed to the re-announce thread")
+    }
+
+    pub fn start(&mut self, response_sender: Sender<AnnounceResponse>){
+        if let Some(tracker) = self.tracker.take(){
+            self.handle = Some(Tracker::start_reannounce(tracker, response_sender));
+        }
     }
 }
 
-impl Clone for Tracker{
-    fn clone(&self) -> Self {
-        Tracker{
-            torrent_mutex: self.torrent_mutex.clone(),
-            schedule_handle: None,
-            interval: self.interval
+impl Drop for Announcer{
+    fn drop(&mut self){
+        self.stop.store(true, Ordering::Relaxed);
+        // nothing was downloaded before the re-announce thread started, so there is only stopped to send
+        if let Some(mut tracker) = self.tracker.take(){
+            self.handle = Some(thread::spawn(move || tracker.finish(false)));
+        }
+
+        // an announce that is still going or a slow tracker doesn't get to hold up shutting down for long
+        if let Some(handle) = self.handle.take(){
+            let deadline = Instant::now() + SHUTDOWN_WAIT;
+            while !handle.is_finished() && Instant::now() < deadline{
+                thread::sleep(Duration::from_millis(50));
+            }
+            if handle.is_finished(){
+                handle.join().unwrap_or(());
+            }
         }
     }
+}
 
-    fn clone_from(&mut self, _source: &Self) {
-        unimplemented!()
+#[cfg(test)]
+mod tracker_tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::unbounded;
+    use crate::torrent_builder::TestDir;
+    use crate::torrent::Torrent;
+    use crate::tracker::{Tracker, Announcer, TrackerPeer, AnnounceEvent, AnnounceResponse, RetryIn, ScrapeStats, scrape_url, udp_timeout, UDP_BASE_TIMEOUT, UDP_REQUEST_BUDGET, UDP_STOPPED_BUDGET};
+    use crate::utils::{bytes_to_i32, i32_to_bytes, i64_to_bytes, u32_to_bytes};
+
+    fn announce_body(peers: Vec<u8>) -> Vec<u8>{
+        let mut body = format!("d8:intervali900e12:min intervali1200e5:peers{}:", peers.len()).into_bytes();
+        body.extend(&peers);
+        body.extend(b"e");
+        body
     }
-}
\ No newline at end of file
+
+    // an http tracker answering a request with each of the bodies in turn, returns the requests it saw
+    fn serve(bodies: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<String>>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/announce", listener.local_addr().unwrap().port());
+        let handle = thread::spawn(move ||{
+            let mut requests = Vec::new();
+            for (body, stream) in bodies.into_iter().zip(listener.incoming()){
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(stream.try_clone().unwrap()).read_line(&mut request).unwrap();
+                requests.push(request);
+
+                let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                response.extend(&body);
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    fn tracker(name: &str, tiers: Vec<Vec<String>>) -> Tracker{
+        let dir = TestDir::new(&format!("tracker-{}", name), &[("a.bin", vec![7u8; 1000])]);
+        let info = dir.torrent("a.bin", |e| tiers.into_iter().fold(e, |builder, tier| builder.announce_tier(tier)));
+
+        let torrent = Torrent::new(info);
+        let guard = torrent.lock().unwrap();
+        Tracker::new(&guard)
+    }
+
+    // a port nothing is listening on
+    fn dead_url() -> String{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://127.0.0.1:{}/announce", listener.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn test_interval_and_peers(){
+        let (url, server) = serve(vec![announce_body(vec![127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]); 2]);
+        let mut tracker = tracker("interval", vec![vec![url]]);
+
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(response.peers, vec![TrackerPeer{ip: "127.0.0.1".to_string(), port: 6881, peer_id: None, info_hash: tracker.info_hashes[0]}, TrackerPeer{ip: "10.0.0.2".to_string(), port: 6882, peer_id: None, info_hash: tracker.info_hashes[0]}]);
+        assert_eq!(response.min_interval, Some(1200));
+        // min interval wins over a shorter interval
+        assert_eq!(tracker.interval, 1200);
+
+        tracker.stats.downloaded.store(600, std::sync::atomic::Ordering::Relaxed);
+        tracker.stats.left.store(400, std::sync::atomic::Ordering::Relaxed);
+        tracker.announce(AnnounceEvent::None).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("left=1000") && requests[0].contains("event=started"));
+        assert!(requests[1].contains("downloaded=600") && requests[1].contains("left=400") && !requests[1].contains("event="));
+    }
+
+    #[test]
+    fn test_failover(){
+        let dead = dead_url();
+        let (first, _first_server) = serve(vec![announce_body(vec![127, 0, 0, 1, 0x1a, 0xe1]); 2]);
+        let (second, _second_server) = serve(vec![announce_body(vec![127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 3, 0x1a, 0xe3]); 1]);
+
+        // the dead tracker in the first tier is skipped over
+        let mut tracker = tracker("failover", vec![vec![dead.clone()], vec![first.clone()], vec![second.clone()]]);
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(tracker.errors.len(), 1);
+        assert_eq!(tracker.errors[0].0, dead);
+
+        // everyone is asked and the peers they share are only listed once
+        tracker.announce_to_all = true;
+        let response = tracker.announce(AnnounceEvent::None).unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(tracker.errors.len(), 1);
+
+        tracker.announce_list.tiers = vec![vec![dead_url()]];
+        assert!(tracker.announce(AnnounceEvent::None).is_err());
+        assert_eq!(tracker.interval, 60);
+    }
+
+    #[test]
+    fn test_scrape(){
+        assert_eq!(scrape_url("http://example.com/announce").unwrap(), "http://example.com/scrape");
+        assert_eq!(scrape_url("http://example.com/x/announce.php?key=1").unwrap(), "http://example.com/x/scrape.php?key=1");
+        assert_eq!(scrape_url("http://example.com/announce_x").unwrap(), "http://example.com/scrape_x");
+        assert!(scrape_url("http://example.com/a").is_none());
+
+        let known = [0xaa; 20];
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend(&known);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei3eeee");
+        let (url, server) = serve(vec![body]);
+
+        let stats = Tracker::scrape_tracker(&url, &[known, [0xbb; 20]]).unwrap();
+        assert_eq!(stats, vec![(known, ScrapeStats{seeders: 5, leechers: 3, downloaded: 50})]);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /scrape?info_hash=%AA"));
+        assert!(requests[0].contains("&info_hash=%BB"));
+    }
+
+    #[test]
+    fn test_udp_scrape(){
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let server = thread::spawn(move ||{
+            let mut packet_sizes = Vec::new();
+            let mut buf = [0u8; 2048];
+            loop{
+                let (size, from) = socket.recv_from(&mut buf).unwrap();
+                let transaction_id = bytes_to_i32(&buf[12..16]);
+                let mut response: Vec<u8> = Vec::new();
+                match bytes_to_i32(&buf[8..12]){
+                    0 => {
+                        response.extend(i32_to_bytes(0));
+                        response.extend(i32_to_bytes(transaction_id));
+                        response.extend(i64_to_bytes(42));
+                    },
+                    2 if buf[16] == 0xee => {
+                        response.extend(i32_to_bytes(3));
+                        response.extend(i32_to_bytes(transaction_id));
+                        response.extend(b"unknown torrent");
+                        socket.send_to(&response, from).unwrap();
+                        return packet_sizes;
+                    },
+                    _ => {
+                        packet_sizes.push((size - 16) / 20);
+                        response.extend(i32_to_bytes(2));
+                        response.extend(i32_to_bytes(transaction_id));
+                        for i in 0..(size - 16) / 20{
+                            response.extend(u32_to_bytes(buf[16 + i * 20] as u32));
+                            response.extend(u32_to_bytes(7));
+                            response.extend(u32_to_bytes(1));
+                        }
+                    }
+                };
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+
+        // more hashes than fit in one packet
+        let info_hashes: Vec<[u8; 20]> = (0..80).map(|i| [i as u8; 20]).collect();
+        let stats = Tracker::scrape_tracker(&url, &info_hashes).unwrap();
+        assert_eq!(stats.len(), 80);
+        assert_eq!(stats[79], ([79; 20], ScrapeStats{seeders: 79, leechers: 1, downloaded: 7}));
+
+        let error = Tracker::scrape_tracker(&url, &[[0xee; 20]]).unwrap_err();
+        assert!(error.details.ends_with("unknown torrent"));
+        assert_eq!(server.join().unwrap(), vec![74, 6]);
+    }
+
+    #[test]
+    fn test_udp_retransmit(){
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let server = thread::spawn(move ||{
+            // (connects, scrapes) received, the first of each is dropped
+            let (mut connects, mut scrapes) = (0, 0);
+            let mut buf = [0u8; 2048];
+            while scrapes < 3{
+                let (_, from) = socket.recv_from(&mut buf).unwrap();
+                let transaction_id = bytes_to_i32(&buf[12..16]);
+                let mut response: Vec<u8> = Vec::new();
+                if bytes_to_i32(&buf[8..12]) == 0{
+                    connects += 1;
+                    if connects == 1{
+                        continue;
+                    }
+                    response.extend(i32_to_bytes(0));
+                    response.extend(i32_to_bytes(transaction_id));
+                    response.extend(i64_to_bytes(42));
+                }
+                else{
+                    assert_eq!(&buf[0..8], &i64_to_bytes(42)[..]);
+                    scrapes += 1;
+                    if scrapes == 1{
+                        continue;
+                    }
+                    response.extend(i32_to_bytes(2));
+                    response.extend(i32_to_bytes(transaction_id));
+                    response.extend(u32_to_bytes(1));
+                    response.extend(u32_to_bytes(2));
+                    response.extend(u32_to_bytes(3));
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+            (connects, scrapes)
+        });
+
+        let stats = Tracker::scrape_tracker(&url, &[[1; 20]]).unwrap();
+        assert_eq!(stats[0].1, ScrapeStats{seeders: 1, downloaded: 2, leechers: 3});
+
+        // the connection id is still fresh so there is no second connect
+        Tracker::scrape_tracker(&url, &[[1; 20]]).unwrap();
+        assert_eq!(server.join().unwrap(), (2, 3));
+    }
+
+    #[test]
+    fn test_udp_concurrent_requests(){
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let server = thread::spawn(move ||{
+            let mut buf = [0u8; 2048];
+            let mut scrapes = Vec::new();
+            while scrapes.len() < 3{
+                let (_, from) = socket.recv_from(&mut buf).unwrap();
+                let mut response: Vec<u8> = Vec::new();
+                if bytes_to_i32(&buf[8..12]) == 0{
+                    response.extend(i32_to_bytes(0));
+                    response.extend(&buf[12..16]);
+                    response.extend(i64_to_bytes(42));
+                    socket.send_to(&response, from).unwrap();
+                } else {
+                    // seeders is the first byte of the info hash so each scrape can tell its answer apart
+                    response.extend(i32_to_bytes(2));
+                    response.extend(&buf[12..16]);
+                    response.extend(u32_to_bytes(buf[16] as u32));
+                    response.extend(u32_to_bytes(0));
+                    response.extend(u32_to_bytes(0));
+                    // the first scrape only gets the connection id cached
+                    if scrapes.is_empty(){
+                        socket.send_to(&response, from).unwrap();
+                    }
+                    scrapes.push((response, from));
+                }
+            }
+
+            // answer in the opposite order, neither request may swallow the other's response
+            for (response, from) in scrapes[1..].iter().rev(){
+                socket.send_to(response, from).unwrap();
+            }
+            socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            let mut retransmits = 0;
+            while socket.recv_from(&mut buf).is_ok(){
+                retransmits += 1;
+            }
+            retransmits
+        });
+
+        Tracker::scrape_tracker(&url, &[[3; 20]]).unwrap();
+        let first_url = url.clone();
+        let first = thread::spawn(move || Tracker::scrape_tracker(&first_url, &[[1; 20]]).unwrap());
+        let second = Tracker::scrape_tracker(&url, &[[2; 20]]).unwrap();
+        assert_eq!(first.join().unwrap()[0].1.seeders, 1);
+        assert_eq!(second[0].1.seeders, 2);
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_udp_budget(){
+        // the budget runs through the whole BEP 15 schedule, the last retransmission still waits 15 * 2 ^ 8 seconds
+        let deadline = Instant::now() + UDP_BASE_TIMEOUT * UDP_REQUEST_BUDGET;
+        assert_eq!(udp_timeout(8, deadline), UDP_BASE_TIMEOUT * 256);
+        assert_eq!((0..9).map(|n| 2u32.pow(n)).sum::<u32>(), UDP_REQUEST_BUDGET);
+
+        // while a tracker that never answers only holds up stopped for its short budget
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut tracker = tracker("budget", vec![vec![format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port())]]);
+        tracker.started.push((tracker.announce_list.urls()[0].clone(), tracker.info_hashes[0]));
+
+        let start = Instant::now();
+        assert!(tracker.announce(AnnounceEvent::Stopped).is_err());
+        assert!(start.elapsed() >= UDP_BASE_TIMEOUT * UDP_STOPPED_BUDGET);
+        assert!(start.elapsed() < UDP_BASE_TIMEOUT * (UDP_STOPPED_BUDGET + 10));
+    }
+
+    #[test]
+    fn test_udp_address_fallback(){
+        // the IPv6 address swallows everything, the IPv4 one on the same port answers
+        let answering = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = answering.local_addr().unwrap().port();
+        let silent = UdpSocket::bind(format!("[::1]:{}", port)).unwrap();
+        thread::spawn(move ||{
+            let mut buf = [0u8; 2048];
+            let (_, from) = answering.recv_from(&mut buf).unwrap();
+            let mut response: Vec<u8> = Vec::new();
+            response.extend(i32_to_bytes(0));
+            response.extend(&buf[12..16]);
+            response.extend(i64_to_bytes(42));
+            answering.send_to(&response, from).unwrap();
+        });
+
+        let addresses = vec![silent.local_addr().unwrap(), format!("127.0.0.1:{}", port).parse().unwrap()];
+        let (socket, connection_id) = Tracker::udp_connect_addresses(&addresses, "udp://localhost", Instant::now() + UDP_BASE_TIMEOUT * UDP_REQUEST_BUDGET).unwrap();
+        assert_eq!(connection_id, 42);
+        assert!(socket.peer_addr().unwrap().is_ipv4());
+
+        // the IPv6 address was given a single try
+        silent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buf = [0u8; 64];
+        assert!(silent.recv_from(&mut buf).is_ok());
+        assert!(silent.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_peers(){
+        let ipv6_peer = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1];
+        let mut body = b"d8:intervali900e5:peers6:".to_vec();
+        body.extend(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend(b"6:peers618:");
+        body.extend(&ipv6_peer);
+        body.extend(b"e");
+        let (url, _server) = serve(vec![body]);
+
+        let mut tracker = tracker("ipv6", vec![vec![url]]);
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(response.peers, vec![TrackerPeer{ip: "127.0.0.1".to_string(), port: 6881, peer_id: None, info_hash: tracker.info_hashes[0]}, TrackerPeer{ip: "2001:db8::1".to_string(), port: 6881, peer_id: None, info_hash: tracker.info_hashes[0]}]);
+
+        // a UDP tracker reached over IPv6 answers with 18 byte peers
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        tracker.announce_list.tiers = vec![vec![format!("udp://[::1]:{}", socket.local_addr().unwrap().port())]];
+        thread::spawn(move ||{
+            let mut buf = [0u8; 2048];
+            for _ in 0..2{
+                let (_, from) = socket.recv_from(&mut buf).unwrap();
+                let action = bytes_to_i32(&buf[8..12]);
+                let mut response: Vec<u8> = Vec::new();
+                response.extend(i32_to_bytes(action));
+                response.extend(&buf[12..16]);
+                if action == 0{
+                    response.extend(i64_to_bytes(42));
+                } else {
+                    response.extend(i32_to_bytes(600));
+                    response.extend(i32_to_bytes(0));
+                    response.extend(i32_to_bytes(1));
+                    response.extend(&ipv6_peer);
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+
+        let response = tracker.announce(AnnounceEvent::None).unwrap();
+        assert_eq!(response.peers, vec![TrackerPeer{ip: "2001:db8::1".to_string(), port: 6881, peer_id: None, info_hash: tracker.info_hashes[0]}]);
+        assert_eq!(tracker.interval, 600);
+    }
+
+    #[test]
+    fn test_peers_keep_their_info_hash(){
+        let (url, _server) = serve(vec![announce_body(vec![127, 0, 0, 1, 0x1a, 0xe1]), announce_body(vec![10, 0, 0, 2, 0x1a, 0xe2])]);
+        let mut tracker = tracker("hashes", vec![vec![url]]);
+        // a hybrid torrent is announced under its v1 hash and then its truncated v2 hash
+        tracker.info_hashes = vec![[1; 20], [2; 20]];
+
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(response.peers.iter().map(|e| (e.port, e.info_hash)).collect::<Vec<(u16, [u8; 20])>>(), vec![(6881, [1; 20]), (6882, [2; 20])]);
+    }
+
+    #[test]
+    fn test_dictionary_peers(){
+        let body = b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:AAAAAAAAAAAAAAAAAAAA4:porti6881eed2:ip11:2001:db8::14:porti6882eeee".to_vec();
+        let (url, _server) = serve(vec![body]);
+
+        let mut tracker = tracker("dictionary", vec![vec![url]]);
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(response.peers, vec![
+            TrackerPeer{ip: "127.0.0.1".to_string(), port: 6881, peer_id: Some(vec![b'A'; 20]), info_hash: tracker.info_hashes[0]},
+            TrackerPeer{ip: "2001:db8::1".to_string(), port: 6882, peer_id: None, info_hash: tracker.info_hashes[0]}
+        ]);
+    }
+
+    #[test]
+    fn test_tracker_messages(){
+        let bodies = vec![
+            b"d8:completei4e10:incompletei9e8:intervali900e5:peers0:10:tracker id3:abc15:warning message9:slow downe".to_vec(),
+            announce_body(vec![]),
+            b"d14:failure reason12:unregistered8:retry in5:nevere".to_vec()
+        ];
+        let (url, server) = serve(bodies);
+        let mut tracker = tracker("messages", vec![vec![url.clone()]]);
+
+        let response = tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!((response.seeders, response.leechers), (Some(4), Some(9)));
+        assert_eq!(response.warnings, vec![(url.clone(), "slow down".to_string())]);
+        tracker.announce(AnnounceEvent::None).unwrap();
+
+        let error = tracker.announce(AnnounceEvent::None).unwrap_err();
+        assert!(tracker.errors[0].1.details.contains("unregistered"));
+        assert!(error.details.contains("None of the 1 trackers responded"));
+
+        // the tracker said never to come back so it isn't contacted again
+        tracker.announce(AnnounceEvent::None).unwrap_err();
+        assert!(tracker.errors.is_empty());
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("trackerid"));
+        assert!(requests[1].contains("trackerid=abc"));
+    }
+
+    #[test]
+    fn test_deeply_nested_response(){
+        let (url, server) = serve(vec![[vec![b'l'; 100000], vec![b'e'; 100000]].concat()]);
+        let mut tracker = tracker("nested", vec![vec![url]]);
+
+        // rejected before the recursive parser gets to run out of stack
+        assert!(tracker.announce(AnnounceEvent::None).is_err());
+        assert!(tracker.errors[0].1.details.ends_with("Tracker sent invalid bencode"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_huge_retry_in(){
+        let mut tracker = tracker("retry", vec![vec![dead_url()]]);
+        let response = AnnounceResponse{failure_reason: Some(String::from("busy")), retry_in: Some(RetryIn::Minutes(u64::MAX)), ..Default::default()};
+
+        // further away than an Instant can go, so treated like never
+        assert!(tracker.accept("http://tracker.example.com/announce", response).is_err());
+        assert!(!tracker.can_announce("http://tracker.example.com/announce"));
+    }
+
+    #[test]
+    fn test_lifecycle_events(){
+        let (url, server) = serve(vec![announce_body(vec![]); 4]);
+        let mut tracker = tracker("lifecycle", vec![vec![url]]);
+        tracker.port = 6881;
+        tracker.key = 0xdeadbeef;
+
+        for event in [AnnounceEvent::None, AnnounceEvent::None, AnnounceEvent::Completed, AnnounceEvent::Stopped].iter(){
+            tracker.announce(*event).unwrap();
+        }
+
+        let requests = server.join().unwrap();
+        // nothing was announced yet, so the first regular announce goes out as started
+        assert!(requests[0].contains("event=started"));
+        assert!(!requests[1].contains("event="));
+        assert!(requests[2].contains("event=completed"));
+        assert!(requests[3].contains("event=stopped"));
+        assert!(requests.iter().all(|e| e.contains("key=deadbeef") && e.contains("port=6881")));
+    }
+
+    #[test]
+    fn test_started_per_tracker(){
+        let (first, first_server) = serve(vec![announce_body(vec![]); 1]);
+        let (second, second_server) = serve(vec![announce_body(vec![]); 2]);
+        let mut tracker = tracker("started-per-tracker", vec![vec![first], vec![second]]);
+
+        // the first tracker goes away after hearing started, the one we fail over to has never heard it
+        tracker.announce(AnnounceEvent::None).unwrap();
+        assert!(first_server.join().unwrap()[0].contains("event=started"));
+        tracker.announce(AnnounceEvent::None).unwrap();
+        tracker.announce(AnnounceEvent::Stopped).unwrap();
+
+        let requests = second_server.join().unwrap();
+        assert!(requests[0].contains("event=started"));
+        assert!(requests[1].contains("event=stopped"));
+    }
+
+    // a udp tracker answering every announce, returns the events it heard
+    fn serve_udp(announces: usize) -> (String, JoinHandle<Vec<i32>>){
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let handle = thread::spawn(move ||{
+            let mut events = Vec::new();
+            let mut buf = [0u8; 2048];
+            while events.len() < announces{
+                let (_, from) = socket.recv_from(&mut buf).unwrap();
+                let action = bytes_to_i32(&buf[8..12]);
+                let mut response: Vec<u8> = Vec::new();
+                response.extend(i32_to_bytes(action));
+                response.extend(&buf[12..16]);
+                if action == 0{
+                    response.extend(i64_to_bytes(42));
+                }
+                else{
+                    events.push(bytes_to_i32(&buf[80..84]));
+                    response.extend(i32_to_bytes(1800));
+                    response.extend(u32_to_bytes(0));
+                    response.extend(u32_to_bytes(0));
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+            events
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_announcer_lifecycle(){
+        let (http, http_server) = serve(vec![announce_body(vec![]); 3]);
+        let (udp, udp_server) = serve_udp(3);
+        let mut announcer = Announcer::new(tracker("announcer", vec![vec![http], vec![udp]]));
+        announcer.tracker().announce_to_all = true;
+        announcer.tracker().announce(AnnounceEvent::None).unwrap();
+
+        // the download finishes while the re-announce thread runs, dropping it sends completed and stopped
+        let (sender, _receiver) = unbounded();
+        let stats = announcer.tracker().stats.clone();
+        announcer.start(sender);
+        stats.left.store(0, std::sync::atomic::Ordering::Relaxed);
+        drop(announcer);
+
+        let requests = http_server.join().unwrap();
+        assert!(requests[0].contains("event=started"));
+        assert!(requests[1].contains("event=completed"));
+        assert!(requests[2].contains("event=stopped"));
+        assert_eq!(udp_server.join().unwrap(), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn test_announcer_stops_without_completing(){
+        let (url, server) = serve(vec![announce_body(vec![]); 2]);
+        let mut announcer = Announcer::new(tracker("announcer-early", vec![vec![url]]));
+        announcer.tracker().announce(AnnounceEvent::None).unwrap();
+
+        // nothing was downloaded, the download ending early only sends stopped
+        announcer.tracker().stats.left.store(0, std::sync::atomic::Ordering::Relaxed);
+        drop(announcer);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("event=started"));
+        assert!(requests[1].contains("event=stopped"));
+    }
+
+    // everything the URLData options of an announce carry, NOP options are skipped
+    fn read_url_data(options: &[u8]) -> String{
+        let mut url_data: Vec<u8> = Vec::new();
+        let mut i = 0;
+        while i < options.len(){
+            match options[i]{
+                0 => break,
+                1 => i += 1,
+                _ => {
+                    let len = options[i + 1] as usize;
+                    url_data.extend(&options[i + 2 .. i + 2 + len]);
+                    i += 2 + len;
+                }
+            };
+        }
+        String::from_utf8(url_data).unwrap()
+    }
+
+    #[test]
+    fn test_udp_url_data(){
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let path = format!("/announce/{}?passkey=abc", "x".repeat(300));
+        let mut tracker = tracker("url-data", vec![vec![format!("udp://127.0.0.1:{}{}", socket.local_addr().unwrap().port(), path)]]);
+
+        let server = thread::spawn(move ||{
+            let mut buf = [0u8; 2048];
+            let mut url_data = String::new();
+            for _ in 0..2{
+                let (size, from) = socket.recv_from(&mut buf).unwrap();
+                let action = bytes_to_i32(&buf[8..12]);
+                let mut response: Vec<u8> = Vec::new();
+                response.extend(i32_to_bytes(action));
+                response.extend(&buf[12..16]);
+                if action == 0{
+                    response.extend(i64_to_bytes(42));
+                } else {
+                    // the announce request itself is 98 bytes, the options follow it
+                    url_data = read_url_data(&buf[98..size]);
+                    response.extend(i32_to_bytes(600));
+                    response.extend(i32_to_bytes(0));
+                    response.extend(i32_to_bytes(0));
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+            url_data
+        });
+
+        tracker.announce(AnnounceEvent::Started).unwrap();
+        assert_eq!(server.join().unwrap(), path);
+    }
+}
@@ -2,6 +2,7 @@ use crossbeam_queue::ArrayQueue;
 
 use colored::Colorize;
 use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
 use std::io::Cursor;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use crossbeam_channel::{Sender, Receiver};
@@ -72,15 +73,64 @@ pub fn i64_to_bytes(integer: i64) -> Vec<u8> {
     bytes
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MetainfoError{
+    ZeroPieceLength,
+    PieceLengthNotPowerOfTwo(u64),
+    InvalidPiecesLength(usize),
+    PieceCountMismatch{expected: usize, found: usize}
+}
+
+impl std::fmt::Display for MetainfoError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            MetainfoError::ZeroPieceLength => write!(f, "piece length is zero"),
+            MetainfoError::PieceLengthNotPowerOfTwo(e) => write!(f, "piece length {} is not a power of two", e),
+            MetainfoError::InvalidPiecesLength(e) => write!(f, "pieces is {} bytes which is not a multiple of 20", e),
+            MetainfoError::PieceCountMismatch{expected, found} => write!(f, "the files need {} pieces but there are {} piece hashes", expected, found)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TorrentError{
-    pub details: String
+    pub details: String,
+    pub reason: Option<MetainfoError>
 }
 
 impl TorrentError{
     pub fn new(msg: String) -> TorrentError{
         TorrentError{
-            details: format!("[{}] ", "X".red()) + &msg
+            details: format!("[{}] ", "X".red()) + &msg,
+            reason: None
+        }
+    }
+
+    // a torrent file that was rejected, keeping why so callers don't have to match on the message
+    pub fn invalid(reason: MetainfoError) -> TorrentError{
+        TorrentError{
+            details: format!("[{}] Invalid torrent: {}", "X".red(), reason),
+            reason: Some(reason)
+        }
+    }
+}
+
+// the byte counts reported to trackers, shared between the download and the re-announce thread
+#[derive(Debug, Default)]
+pub struct TransferStats{
+    pub downloaded: AtomicU64,
+    // requests from peers are never served yet so this stays 0, seeding would add to it
+    pub uploaded: AtomicU64,
+    pub left: AtomicU64
+}
+
+impl TransferStats{
+    pub fn new(left: u64) -> TransferStats{
+        TransferStats{
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            left: AtomicU64::new(left)
         }
     }
 }
@@ -92,7 +142,9 @@ pub enum TorrentEventType{
     Downloaded,
     Request,
     Cancel,
-    Exit
+    Exit,
+    // a tracker accepted the announce but sent a warning message along with it
+    TrackerWarning
 }
 
 #[derive(Debug, Clone)]
@@ -129,21 +181,33 @@ pub struct TorrentPiece{
 #[derive(Debug, Clone)]
 pub struct TorrentEvent{
     pub msg_type: TorrentEventType,
-    pub index_downloaded: Option<u32>
+    pub index_downloaded: Option<u32>,
+    // the tracker url and its message for TrackerWarning
+    pub tracker_warning: Option<(String, String)>
 }
 
 impl TorrentEvent{
     pub fn new(msg_type: TorrentEventType) -> TorrentEvent{
         TorrentEvent{
             msg_type,
-            index_downloaded: None
+            index_downloaded: None,
+            tracker_warning: None
         }
     }
 
     pub fn with_index(msg_type: TorrentEventType, index: u32) -> TorrentEvent{
         TorrentEvent{
             msg_type,
-            index_downloaded: Some(index)
+            index_downloaded: Some(index),
+            tracker_warning: None
+        }
+    }
+
+    pub fn tracker_warning(url: &str, message: &str) -> TorrentEvent{
+        TorrentEvent{
+            msg_type: TorrentEventType::TrackerWarning,
+            index_downloaded: None,
+            tracker_warning: Some((url.to_string(), message.to_string()))
         }
     }
 }
\ No newline at end of file
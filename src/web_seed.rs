@@ -0,0 +1,369 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use percent_encoding::{percent_decode_str, percent_encode_byte};
+
+use crate::torrent_file::{TorrentInfo, TorrentFile};
+use crate::piece_source;
+use crate::piece_source::FetchError;
+use crate::utils::{TorrentChannel, TorrentError, TorrentEvent};
+
+const REQUEST_TIMEOUT: u64 = 30;
+
+// a BEP 19 web seed, pieces are read straight out of the files on an HTTP or FTP server
+#[derive(Debug, Clone)]
+pub struct WebSeed{
+    pub url: String,
+    info: TorrentInfo
+}
+
+// urls use the raw bytes of each path component, the server's names may not be utf-8 either
+fn encode_path(path: &[Vec<u8>]) -> String{
+    path.iter().map(|segment| {
+        let mut resp = String::new();
+        for byte in segment{
+            let c = char::from(*byte);
+            if byte.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '~' {
+                resp.push(c);
+            } else {
+                resp += percent_encode_byte(*byte);
+            }
+        }
+        resp
+    }).collect::<Vec<String>>().join("/")
+}
+
+fn ftp_reply(reader: &mut BufReader<TcpStream>) -> Result<(u32, String), TorrentError>{
+    let mut reply = String::new();
+    loop{
+        let mut line = String::new();
+        match reader.read_line(&mut line){
+            Ok(0) => return Err(TorrentError::new(String::from("FTP server closed the connection"))),
+            Ok(_) => (),
+            Err(e) => return Err(TorrentError::new(e.to_string()))
+        };
+        reply += &line;
+
+        // multi line replies start with "xyz-" and end with a line starting with "xyz "
+        if line.len() >= 4 && line.as_bytes()[3] == b' ' && reply.starts_with(&line[..3]){
+            break;
+        }
+    }
+
+    match reply[..3].parse(){
+        Ok(e) => Ok((e, reply)),
+        _ => Err(TorrentError::new(format!("Invalid FTP reply: {}", reply.trim())))
+    }
+}
+
+fn ftp_command(reader: &mut BufReader<TcpStream>, command: &str, expected: &[u32]) -> Result<String, TorrentError>{
+    if let Err(e) = reader.get_mut().write_all(format!("{}\r\n", command).as_bytes()){
+        return Err(TorrentError::new(e.to_string()));
+    }
+
+    let (code, reply) = match ftp_reply(reader){
+        Ok(e) => e,
+        Err(e) => return Err(e)
+    };
+
+    if !expected.contains(&code){
+        return Err(TorrentError::new(format!("Unexpected FTP reply to {}: {}", command.split(' ').next().unwrap_or(""), reply.trim())));
+    }
+
+    Ok(reply)
+}
+
+// the data connection address from a reply like "227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)"
+fn parse_pasv(reply: &str) -> Option<(String, u16)>{
+    let start = reply.find('(')? + 1;
+    let end = reply[start..].find(')')? + start;
+    let parts: Vec<u16> = reply[start..end].split(',').filter_map(|e| e.trim().parse().ok()).collect();
+    if parts.len() != 6{
+        return None;
+    }
+
+    Some((format!("{}.{}.{}.{}", parts[0], parts[1], parts[2], parts[3]), (parts[4] << 8) + parts[5]))
+}
+
+impl WebSeed{
+    pub fn new(url: &str, info: &TorrentInfo) -> WebSeed{
+        WebSeed{
+            url: url.to_string(),
+            info: info.clone(),
+        }
+    }
+
+    // single file torrents may point directly at the file, otherwise the torrent's paths are appended to the url
+    fn file_url(&self, file: &TorrentFile) -> String{
+        if self.info.is_single_file() && !self.url.ends_with('/'){
+            return self.url.clone();
+        }
+
+        let separator = if self.url.ends_with('/') { "" } else { "/" };
+        format!("{}{}{}", self.url, separator, encode_path(&file.raw_path))
+    }
+
+    // the (file index, offset in file, length) ranges that make up a piece
+    fn piece_ranges(&self, index: usize, size: u64) -> Vec<(usize, u64, u64)>{
+        let mut ret: Vec<(usize, u64, u64)> = Vec::new();
+
+        // v2 only torrents have no padding files but their pieces never span files
+        if self.info.hashes.is_empty(){
+            if let Some(v2) = &self.info.v2{
+                if let Some((file, piece)) = v2.piece_location(index){
+                    let file_index = v2.files.iter().position(|e| e.path == file.path).unwrap_or(0);
+                    ret.push((file_index, piece as u64 * self.info.piece_byte_size, size));
+                }
+                return ret;
+            }
+        }
+
+        let mut start = index as u64 * self.info.piece_byte_size;
+        let mut remaining = size;
+        let mut file_start = 0u64;
+        for (i, file) in self.info.files.iter().enumerate(){
+            let file_end = file_start + file.size_bytes as u64;
+            if remaining > 0 && start < file_end{
+                let length = remaining.min(file_end - start);
+                ret.push((i, start - file_start, length));
+                start += length;
+                remaining -= length;
+            }
+            file_start = file_end;
+        }
+
+        ret
+    }
+
+    fn fetch_http(url: &str, offset: u64, length: u64) -> Result<Vec<u8>, TorrentError>{
+        let response = match minreq::get(url)
+            .with_header("Range", format!("bytes={}-{}", offset, offset + length - 1))
+            .with_timeout(REQUEST_TIMEOUT)
+            .send(){
+            Ok(e) => e,
+            Err(_) => return Err(TorrentError::new(format!("Unable to get a response from web seed {}", url)))
+        };
+
+        let body = match response.status_code{
+            206 => response.into_bytes(),
+            // the server ignored the range and sent the whole file
+            200 if response.as_bytes().len() as u64 >= offset + length => response.as_bytes()[offset as usize .. (offset + length) as usize].to_vec(),
+            e => return Err(TorrentError::new(format!("Web seed {} responded with status {}", url, e)))
+        };
+
+        if body.len() as u64 != length{
+            return Err(TorrentError::new(format!("Web seed {} sent {} bytes but {} were requested", url, body.len(), length)));
+        }
+
+        Ok(body)
+    }
+
+    fn fetch_ftp(url: &str, offset: u64, length: u64) -> Result<Vec<u8>, TorrentError>{
+        let url_parsed = urlparse::urlparse(url);
+        let host = url_parsed.hostname.unwrap_or_default();
+        let stream = match TcpStream::connect((host.as_str(), url_parsed.port.unwrap_or(21))){
+            Ok(e) => e,
+            Err(e) => return Err(TorrentError::new(e.to_string()))
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT))).unwrap_or(());
+
+        let mut reader = BufReader::new(stream);
+        match ftp_reply(&mut reader){
+            Ok((220, _)) => (),
+            Ok((_, e)) => return Err(TorrentError::new(format!("FTP server refused the connection: {}", e.trim()))),
+            Err(e) => return Err(e)
+        };
+
+        let user = url_parsed.username.unwrap_or(String::from("anonymous"));
+        let password = url_parsed.password.unwrap_or(String::from("anonymous@"));
+        let reply = match ftp_command(&mut reader, &format!("USER {}", user), &[230, 331]){
+            Ok(e) => e,
+            Err(e) => return Err(e)
+        };
+        if reply.starts_with("331"){
+            if let Err(e) = ftp_command(&mut reader, &format!("PASS {}", password), &[230, 202]){
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = ftp_command(&mut reader, "TYPE I", &[200]){
+            return Err(e);
+        }
+
+        let data_addr = match ftp_command(&mut reader, "PASV", &[227]){
+            Ok(e) => match parse_pasv(&e){
+                Some(e) => e,
+                _ => return Err(TorrentError::new(String::from("Invalid FTP passive mode reply")))
+            },
+            Err(e) => return Err(e)
+        };
+
+        let mut data_stream = match TcpStream::connect(data_addr){
+            Ok(e) => e,
+            Err(e) => return Err(TorrentError::new(e.to_string()))
+        };
+        data_stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT))).unwrap_or(());
+
+        if let Err(e) = ftp_command(&mut reader, &format!("REST {}", offset), &[350]){
+            return Err(e);
+        }
+
+        let path = percent_decode_str(&url_parsed.path).decode_utf8_lossy().to_string();
+        if let Err(e) = ftp_command(&mut reader, &format!("RETR {}", path), &[125, 150]){
+            return Err(e);
+        }
+
+        // only read the bytes we need, the server aborts the transfer once we hang up
+        let mut data = vec![0u8; length as usize];
+        if let Err(e) = data_stream.read_exact(&mut data){
+            return Err(TorrentError::new(e.to_string()));
+        }
+
+        Ok(data)
+    }
+
+    pub fn fetch_piece(&self, index: usize, size: u64) -> Result<Vec<u8>, TorrentError>{
+        let mut data: Vec<u8> = Vec::with_capacity(size as usize);
+
+        for (file_index, offset, length) in self.piece_ranges(index, size){
+            let file = &self.info.files[file_index];
+
+            // padding files are all zeros and do not exist on the server
+            if file.is_padding{
+                data.extend(vec![0u8; length as usize]);
+                continue;
+            }
+
+            let url = self.file_url(file);
+            let bytes = if url.starts_with("ftp"){
+                WebSeed::fetch_ftp(&url, offset, length)
+            } else {
+                WebSeed::fetch_http(&url, offset, length)
+            };
+
+            match bytes{
+                Ok(e) => data.extend(e),
+                Err(e) => return Err(e)
+            };
+        }
+
+        if data.len() as u64 != size{
+            return Err(TorrentError::new(format!("Unable to map piece {} onto the files of web seed {}", index, self.url)));
+        }
+
+        Ok(data)
+    }
+
+    // works through the same queue as the peers so the torrent can finish even without any seeders
+    pub fn start_download(seed: WebSeed, channel: TorrentChannel<TorrentEvent>, output: Arc<Mutex<Vec<u8>>>) -> JoinHandle<()>{
+        let name = format!("web seed {}", seed.url);
+        let piece_byte_size = seed.info.piece_byte_size;
+        piece_source::start_download(name, piece_byte_size, move |index, size| match seed.fetch_piece(index, size){
+            Ok(e) => Ok(e),
+            Err(e) => Err(FetchError::Failed(e))
+        }, channel, output)
+    }
+}
+
+#[cfg(test)]
+mod web_seed_tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use percent_encoding::percent_decode_str;
+    use std::sync::{Arc, Mutex};
+
+    use crossbeam_channel::{bounded, unbounded};
+    use crossbeam_queue::ArrayQueue;
+    use crate::torrent_builder::{TestDir, TorrentVersion};
+    use crate::utils::{TorrentChannel, TorrentEvent, TorrentEventType};
+    use crate::web_seed::{WebSeed, encode_path};
+
+    // a tiny HTTP server that answers range requests for the files in `dir`
+    fn serve(dir: std::path::PathBuf) -> u16{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move ||{
+            for stream in listener.incoming(){
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut path = String::new();
+                let mut range = (0usize, 0usize);
+                loop{
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.starts_with("GET "){
+                        path = percent_decode_str(line.split(' ').nth(1).unwrap()).decode_utf8_lossy().to_string();
+                    }
+                    if line.to_lowercase().starts_with("range: bytes="){
+                        let bounds: Vec<usize> = line.trim()[13..].split('-').map(|e| e.parse().unwrap()).collect();
+                        range = (bounds[0], bounds[1]);
+                    }
+                    if line.trim().is_empty(){
+                        break;
+                    }
+                }
+
+                let response = match std::fs::read(dir.join(path.trim_start_matches("/neon/"))){
+                    Ok(e) => {
+                        let body = &e[range.0..=range.1];
+                        let mut resp = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                        resp.extend(body);
+                        resp
+                    },
+                    _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    fn test_encode_path(){
+        // each component is encoded once, a '/' inside a name is not a separator
+        let path = vec![b"d\xe9".to_vec(), b"a%41/b c".to_vec()];
+        assert_eq!(encode_path(&path), "d%E9/a%2541%2Fb%20c");
+    }
+
+    #[test]
+    fn test_fetch_pieces(){
+        let dir = TestDir::new("web-seed", &[
+            ("neon/a.bin", (0..70000).map(|i| (i % 251) as u8).collect()),
+            ("neon/sub/b b.bin", (0..20000).map(|i| (i % 13) as u8).collect())
+        ]);
+
+        let port = serve(dir.path.join("neon"));
+        let info = dir.torrent("neon", |e| e
+            .piece_size(32768)
+            .announce("http://tracker.example.com/announce")
+            .web_seed(&format!("http://127.0.0.1:{}/", port))
+            .version(TorrentVersion::Hybrid));
+
+        let seed = WebSeed::new(&info.url_list[0], &info);
+        for i in 0..info.num_pieces{
+            let piece = seed.fetch_piece(i, info.piece_size(i)).unwrap();
+            assert!(info.verify_piece(i, &piece));
+        }
+    }
+
+    #[test]
+    fn test_exit_when_done(){
+        let dir = TestDir::new("web-seed-exit", &[("a.bin", vec![1u8; 1000])]);
+        let info = dir.torrent("a.bin", |e| e.announce("http://tracker.example.com/announce"));
+
+        let (sender, _events) = unbounded();
+        let (exit, receiver) = bounded(3);
+        let channel = TorrentChannel::new(Arc::new(ArrayQueue::new(1)), sender, receiver);
+        let handle = WebSeed::start_download(WebSeed::new("http://127.0.0.1:1/", &info), channel, Arc::new(Mutex::new(Vec::new())));
+
+        exit.send(TorrentEvent::new(TorrentEventType::Exit)).unwrap();
+        handle.join().unwrap();
+    }
+}